@@ -0,0 +1,276 @@
+//! Concurrent connections benchmark of the proxy core.
+//!
+//! Holds idle keep-alive client connections open through the proxy, then measures
+//! requests of active clients to a local server. With `use-openssl` the same is measured
+//! for HTTP/2 clients over TLS.
+//! Run with `cargo bench --bench connections`. Environment variables:
+//! `IDLE` - comma-separated counts of idle connections (default - 0,100,1000),
+//! `CLIENTS` - active clients (default - 16), `REQUESTS` - requests per client (default - 200)
+
+use std::{
+    env, fs,
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, RwLock},
+    thread,
+    time::{Duration, Instant}
+};
+
+use flowgate::{config::Config, server::FlowgateServer};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+const REQUEST: &[u8] = b"GET / HTTP/1.1\r\nHost: bench\r\nConnection: keep-alive\r\n\r\n";
+
+fn var(name: &str, default: &str) -> String {
+    env::var(name).unwrap_or(default.to_string())
+}
+
+/// Starts keep-alive server answering `ok` to every request
+fn start_upstream() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    listener.set_nonblocking(true).unwrap();
+
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+
+        runtime.block_on(async move {
+            let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut stream = tokio::io::BufReader::new(stream);
+                    let mut line = String::new();
+
+                    loop {
+                        line.clear();
+                        match stream.read_line(&mut line).await {
+                            Ok(0) | Err(_) => break,
+                            Ok(_) if line == "\r\n" => {
+                                let response = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+                                if stream.write_all(response).await.is_err() { break }
+                            },
+                            Ok(_) => {}
+                        }
+                    }
+                });
+            }
+        });
+    });
+
+    port
+}
+
+/// Sends request on keep-alive connection and reads the response
+fn request(stream: &mut BufReader<TcpStream>) -> Option<()> {
+    stream.get_mut().write_all(REQUEST).ok()?;
+
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if stream.read_line(&mut line).ok()? == 0 { return None }
+        if line == "\r\n" { break }
+    }
+
+    let mut body = [0; 2];
+    stream.read_exact(&mut body).ok()
+}
+
+fn connect(port: u16) -> BufReader<TcpStream> {
+    BufReader::new(TcpStream::connect(("127.0.0.1", port)).unwrap())
+}
+
+/// Prints row of the results table
+fn report(count: usize, mut latencies: Vec<Duration>, elapsed: Duration) {
+    latencies.sort();
+
+    let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100].as_secs_f64() * 1000.0;
+
+    println!(
+        "{:>8} {:>10} {:>10.0} {:>10.2} {:>10.2}",
+        count,
+        latencies.len(),
+        latencies.len() as f64 / elapsed.as_secs_f64(),
+        percentile(50),
+        percentile(99)
+    );
+}
+
+/// Writes self-signed certificate and key for `bench` domain, returns config lines using them
+#[cfg(feature = "use-openssl")]
+fn write_certificate() -> String {
+    use openssl::{asn1::Asn1Time, hash::MessageDigest, pkey::PKey, rsa::Rsa, x509::{X509, X509NameBuilder}};
+
+    let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("CN", "bench").unwrap();
+    let name = name.build();
+
+    let mut cert = X509::builder().unwrap();
+    cert.set_version(2).unwrap();
+    cert.set_subject_name(&name).unwrap();
+    cert.set_issuer_name(&name).unwrap();
+    cert.set_pubkey(&key).unwrap();
+    cert.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+    cert.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+    cert.sign(&key, MessageDigest::sha256()).unwrap();
+
+    let cert_file = env::temp_dir().join(format!("flowgate-bench-{}.crt", std::process::id()));
+    let key_file = env::temp_dir().join(format!("flowgate-bench-{}.key", std::process::id()));
+    fs::write(&cert_file, cert.build().to_pem().unwrap()).unwrap();
+    fs::write(&key_file, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+
+    format!("    ssl_cert: {}\n    ssl_key: {}\n", cert_file.display(), key_file.display())
+}
+
+/// Connects HTTP/2 client over TLS
+#[cfg(feature = "use-openssl")]
+async fn connect_h2(connector: &openssl::ssl::SslConnector, port: u16) -> h2::client::SendRequest<bytes::Bytes> {
+    let ssl = connector.configure().unwrap().into_ssl("bench").unwrap();
+    let stream = tokio::net::TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    let mut stream = tokio_openssl::SslStream::new(ssl, stream).unwrap();
+    std::pin::Pin::new(&mut stream).connect().await.unwrap();
+
+    let (client, conn) = h2::client::handshake(stream).await.unwrap();
+    tokio::spawn(conn);
+    client
+}
+
+/// Sends request on HTTP/2 connection and reads the response
+#[cfg(feature = "use-openssl")]
+async fn request_h2(client: &h2::client::SendRequest<bytes::Bytes>) -> Option<()> {
+    let request = http::Request::builder().uri("https://bench/").body(()).ok()?;
+    let (response, _) = client.clone().ready().await.ok()?.send_request(request, true).ok()?;
+
+    let mut body = response.await.ok()?.into_body();
+    while let Some(data) = body.data().await {
+        let data = data.ok()?;
+        body.flow_control().release_capacity(data.len()).ok()?;
+    }
+
+    Some(())
+}
+
+/// Runs the benchmark for HTTP/2 clients, each active client sends its requests on one connection
+#[cfg(feature = "use-openssl")]
+fn bench_h2(port: u16, idle: &[usize], clients: usize, requests: usize) {
+    use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+
+    let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+    connector.set_verify(SslVerifyMode::NONE);
+    connector.set_alpn_protos(b"\x02h2").unwrap();
+    let connector = Arc::new(connector.build());
+
+    let runtime = tokio::runtime::Builder::new_multi_thread().worker_threads(4).enable_all().build().unwrap();
+
+    println!("\nHTTP/2");
+    println!("{:>8} {:>10} {:>10} {:>10} {:>10}", "idle", "requests", "req/s", "p50 ms", "p99 ms");
+
+    runtime.block_on(async move {
+        for &count in idle {
+            let mut held = Vec::with_capacity(count);
+            for _ in 0..count {
+                let client = connect_h2(&connector, port).await;
+                request_h2(&client).await.expect("idle connection failed");
+                held.push(client);
+            }
+
+            let start = Instant::now();
+            let handles: Vec<_> = (0..clients).map(|_| tokio::spawn({
+                let connector = connector.clone();
+
+                async move {
+                    let client = connect_h2(&connector, port).await;
+                    let mut latencies = Vec::with_capacity(requests);
+
+                    for _ in 0..requests {
+                        let time = Instant::now();
+                        request_h2(&client).await.expect("request failed");
+                        latencies.push(time.elapsed());
+                    }
+
+                    latencies
+                }
+            })).collect();
+
+            let mut latencies = Vec::new();
+            for handle in handles {
+                latencies.extend(handle.await.unwrap());
+            }
+
+            report(count, latencies, start.elapsed());
+        }
+    });
+}
+
+fn main() {
+    let idle: Vec<usize> = var("IDLE", "0,100,1000").split(',').map(|o| o.trim().parse().unwrap()).collect();
+    let clients: usize = var("CLIENTS", "16").parse().unwrap();
+    let requests: usize = var("REQUESTS", "200").parse().unwrap();
+
+    let upstream = start_upstream();
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    #[cfg(feature = "use-openssl")]
+    let https_port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+
+    #[cfg(feature = "use-openssl")]
+    let (https, certificate) = (format!("https_host: 127.0.0.1:{https_port}\nhttp2: true\n"), write_certificate());
+    #[cfg(not(feature = "use-openssl"))]
+    let (https, certificate) = (String::new(), String::new());
+
+    let filename = env::temp_dir().join(format!("flowgate-bench-{}.yml", std::process::id()));
+    fs::write(&filename, format!("\
+http_host: 127.0.0.1:{port}
+{https}threadpool_size: 4
+connection_timeout: 300
+sites:
+  - domain: bench
+    host: 127.0.0.1:{upstream}
+{certificate}")).unwrap();
+
+    let config = Config::parse(filename.to_str().unwrap()).map_err(|o| o[0].to_string()).unwrap();
+    for extension in ["yml", "crt", "key"] {
+        let _ = fs::remove_file(filename.with_extension(extension));
+    }
+
+    let server = FlowgateServer::new(Arc::new(RwLock::new(config)));
+    server.start();
+
+    while TcpStream::connect(("127.0.0.1", port)).is_err() {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    println!("threadpool_size 4, {clients} active clients x {requests} requests");
+    println!("\nHTTP/1.1");
+    println!("{:>8} {:>10} {:>10} {:>10} {:>10}", "idle", "requests", "req/s", "p50 ms", "p99 ms");
+
+    for &count in &idle {
+        let mut held = Vec::with_capacity(count);
+        for _ in 0..count {
+            let mut stream = connect(port);
+            request(&mut stream).expect("idle connection failed");
+            held.push(stream);
+        }
+
+        let start = Instant::now();
+        let handles: Vec<_> = (0..clients).map(|_| thread::spawn(move || {
+            let mut stream = connect(port);
+            let mut latencies = Vec::with_capacity(requests);
+
+            for _ in 0..requests {
+                let time = Instant::now();
+                request(&mut stream).expect("request failed");
+                latencies.push(time.elapsed());
+            }
+
+            latencies
+        })).collect();
+
+        let latencies: Vec<Duration> = handles.into_iter().flat_map(|o| o.join().unwrap()).collect();
+        report(count, latencies, start.elapsed());
+    }
+
+    #[cfg(feature = "use-openssl")]
+    bench_h2(https_port, &idle, clients, requests);
+}
@@ -0,0 +1,161 @@
+//! Body forwarding throughput benchmark, `splice(2)` against the copy loop.
+//!
+//! Downloads large responses of a local server directly and through proxies
+//! with `zero_copy` enabled and disabled.
+//! Run with `cargo bench --bench throughput`. Environment variables:
+//! `SIZE` - response size in MiB (default - 256), `CLIENTS` - concurrent clients (default - 4),
+//! `DOWNLOADS` - downloads per client (default - 4)
+
+use std::{
+    env, fs,
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, RwLock},
+    thread,
+    time::{Duration, Instant}
+};
+
+use flowgate::{config::Config, server::FlowgateServer};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+const REQUEST: &[u8] = b"GET / HTTP/1.1\r\nHost: bench\r\nConnection: keep-alive\r\n\r\n";
+
+fn var(name: &str, default: &str) -> String {
+    env::var(name).unwrap_or(default.to_string())
+}
+
+/// Starts keep-alive server answering every request with `size` bytes
+fn start_upstream(size: usize) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    listener.set_nonblocking(true).unwrap();
+
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let body: Arc<[u8]> = vec![b'x'; 1024 * 1024].into();
+
+        runtime.block_on(async move {
+            let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+
+            while let Ok((stream, _)) = listener.accept().await {
+                let body = body.clone();
+
+                tokio::spawn(async move {
+                    let mut stream = tokio::io::BufReader::new(stream);
+                    let mut line = String::new();
+
+                    loop {
+                        line.clear();
+                        match stream.read_line(&mut line).await {
+                            Ok(0) | Err(_) => break,
+                            Ok(_) if line == "\r\n" => {
+                                let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {size}\r\n\r\n");
+                                if stream.write_all(head.as_bytes()).await.is_err() { break }
+
+                                let mut left = size;
+                                while left > 0 {
+                                    let chunk = left.min(body.len());
+                                    if stream.write_all(&body[..chunk]).await.is_err() { return }
+                                    left -= chunk;
+                                }
+                            },
+                            Ok(_) => {}
+                        }
+                    }
+                });
+            }
+        });
+    });
+
+    port
+}
+
+/// Starts proxy to `upstream` and returns it with its port
+fn start_proxy(upstream: u16, zero_copy: bool) -> (FlowgateServer, u16) {
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+
+    let filename = env::temp_dir().join(format!("flowgate-bench-{}-{zero_copy}.yml", std::process::id()));
+    fs::write(&filename, format!("\
+http_host: 127.0.0.1:{port}
+threadpool_size: 4
+connection_timeout: 300
+zero_copy: {zero_copy}
+sites:
+  - domain: bench
+    host: 127.0.0.1:{upstream}
+")).unwrap();
+
+    let config = Config::parse(filename.to_str().unwrap()).map_err(|o| o[0].to_string()).unwrap();
+    let _ = fs::remove_file(&filename);
+
+    let server = FlowgateServer::new(Arc::new(RwLock::new(config)));
+    server.start();
+
+    while TcpStream::connect(("127.0.0.1", port)).is_err() {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    (server, port)
+}
+
+/// Sends request on keep-alive connection and reads the response of `size` bytes
+fn download(stream: &mut BufReader<TcpStream>, size: usize, buf: &mut [u8]) {
+    stream.get_mut().write_all(REQUEST).unwrap();
+
+    let mut line = String::new();
+    loop {
+        line.clear();
+        assert!(stream.read_line(&mut line).unwrap() > 0, "connection closed");
+        if line == "\r\n" { break }
+    }
+
+    let mut left = size;
+    while left > 0 {
+        let chunk = left.min(buf.len());
+        let read = stream.read(&mut buf[..chunk]).unwrap();
+        assert!(read > 0, "connection closed");
+        left -= read;
+    }
+}
+
+/// Downloads through `port` by all clients and returns MiB/s
+fn measure(port: u16, size: usize, clients: usize, downloads: usize) -> f64 {
+    let start = Instant::now();
+
+    let handles: Vec<_> = (0..clients).map(|_| thread::spawn(move || {
+        let mut stream = BufReader::new(TcpStream::connect(("127.0.0.1", port)).unwrap());
+        let mut buf = vec![0; 256 * 1024];
+
+        for _ in 0..downloads {
+            download(&mut stream, size, &mut buf);
+        }
+    })).collect();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    (size * clients * downloads) as f64 / 1024.0 / 1024.0 / start.elapsed().as_secs_f64()
+}
+
+fn main() {
+    let size = var("SIZE", "256").parse::<usize>().unwrap() * 1024 * 1024;
+    let clients: usize = var("CLIENTS", "4").parse().unwrap();
+    let downloads: usize = var("DOWNLOADS", "4").parse().unwrap();
+
+    let upstream = start_upstream(size);
+
+    let (_splice, splice) = start_proxy(upstream, true);
+    let (_copy, copy) = start_proxy(upstream, false);
+
+    println!("{} MiB x {clients} clients x {downloads} downloads", size / 1024 / 1024);
+    println!("{:>10} {:>10}", "path", "MiB/s");
+
+    for (name, port) in [
+        ("direct", upstream),
+        ("splice", splice),
+        ("copy", copy)
+    ] {
+        println!("{:>10} {:>10.0}", name, measure(port, size, clients, downloads));
+    }
+}
@@ -2,4 +2,11 @@ pub mod config;
 pub mod server;
 pub mod ssl_cert;
 pub mod closeable;
-pub mod websocket;
\ No newline at end of file
+pub mod websocket;
+pub mod upstream;
+pub mod forward;
+pub mod upgrade;
+pub mod http2;
+pub mod control;
+pub mod stats;
+pub mod admin;
\ No newline at end of file
@@ -0,0 +1,239 @@
+use std::{io::{Read, Write}, net::{SocketAddr, TcpListener, TcpStream}, sync::{Arc, RwLock}, thread};
+
+use log::{info, warn};
+use serde_json::{json, Value};
+
+use super::{config::{Config, ControlToken}, control::{self, ControlError, ControlGuard, Message}, upgrade};
+
+/// Maps REST endpoint to control message type and domain from the path
+fn route<'a>(method: &str, path: &'a str) -> Option<(&'static str, Option<&'a str>)> {
+    let path: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+    Some(match (method, path.as_slice()) {
+        ("GET", ["sites"]) => ("list_sites", None),
+        ("GET", ["sites", domain]) => ("get_site", Some(*domain)),
+        ("PUT", ["sites", domain]) => ("set_site", Some(*domain)),
+        ("DELETE", ["sites", domain]) => ("delete_site", Some(*domain)),
+        ("PUT", ["sites", domain, "certificate"]) => ("set_certificate", Some(*domain)),
+        ("GET", ["health"]) => ("get_health", None),
+        ("GET", ["stats"]) => ("get_stats", None),
+        ("GET", ["config"]) => ("get_config", None),
+        ("POST", ["config", "save"]) => ("save_config", None),
+        ("POST", ["reload"]) => ("reload", None),
+        _ => return None
+    })
+}
+
+/// Max size of request head, larger requests get 431
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+/// Max size of request body, larger requests get 413
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+fn status(error: &ControlError) -> &'static str {
+    match error.code {
+        "unauthorized" => "403 Forbidden",
+        "not_found" | "unknown_type" => "404 Not Found",
+        "body_too_large" => "413 Payload Too Large",
+        "headers_too_large" => "431 Request Header Fields Too Large",
+        "save_failed" | "internal" => "500 Internal Server Error",
+        _ => "400 Bad Request"
+    }
+}
+
+/// Finds control message type and domain of the endpoint and checks the token
+fn authorize<'a>(
+    config: &Arc<RwLock<Config>>,
+    addr: SocketAddr,
+    method: &str,
+    path: &'a str,
+    auth: Option<&str>
+) -> Result<(&'static str, Option<&'a str>, ControlToken), ControlError> {
+    let (kind, domain) = route(method, path).ok_or(ControlError::new("not_found"))?;
+
+    let token = auth.and_then(|o| o.strip_prefix("Bearer "))
+        .ok_or_else(|| control::unauthorized(kind, addr))?;
+    let token = control::authenticate(config, addr, token)?;
+
+    Ok((kind, domain, token))
+}
+
+fn handle(
+    config: Arc<RwLock<Config>>,
+    addr: SocketAddr,
+    (kind, domain, token): (&str, Option<&str>, ControlToken),
+    body: &[u8]
+) -> Result<Value, ControlError> {
+    let mut data = if body.is_empty() {
+        Message::new()
+    } else {
+        match serde_json::from_slice::<Value>(body) {
+            Ok(Value::Object(data)) => data,
+            _ => return Err(ControlError::new("invalid_json"))
+        }
+    };
+
+    data.insert("type".to_string(), json!(kind));
+    if let Some(domain) = domain {
+        data.insert("domain".to_string(), json!(domain));
+    }
+
+    let reply = control::on_message(config, &token, addr, &data)?;
+
+    if kind == "get_site" && reply.get("site").is_some_and(|o| o.is_null()) {
+        return Err(ControlError::field("not_found", "domain"));
+    }
+
+    Ok(reply)
+}
+
+fn respond(stream: &mut impl Write, status: &str, body: Value) -> Option<()> {
+    let body = body.to_string();
+
+    stream.write_all(format!(
+        "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    ).as_bytes()).ok()
+}
+
+fn accept_stream(
+    config: Arc<RwLock<Config>>,
+    stream: TcpStream,
+    addr: SocketAddr,
+    #[cfg(feature = "use-openssl")]
+    acceptor: Option<openssl::ssl::SslAcceptor>
+) -> Option<()> {
+    let timeout = config.read().ok()?.connection_timeout;
+    stream.set_read_timeout(Some(timeout)).ok()?;
+    stream.set_write_timeout(Some(timeout)).ok()?;
+
+    #[cfg(feature = "use-openssl")]
+    if let Some(acceptor) = acceptor {
+        let mut ssl = openssl::ssl::Ssl::new(acceptor.context()).ok()?;
+        config.read().ok()?.websocket_ssl.as_ref()?.apply(&mut ssl)?;
+
+        let Ok(stream) = ssl.accept(stream) else {
+            warn!("{addr} > admin tls handshake failed");
+            return None;
+        };
+
+        return serve(config, stream, addr);
+    }
+
+    serve(config, stream, addr)
+}
+
+/// Answers one request of admin client
+fn serve(config: Arc<RwLock<Config>>, mut stream: impl Read + Write, addr: SocketAddr) -> Option<()> {
+    let mut head = Vec::new();
+
+    {
+        let mut buf = [0; 1];
+        let mut counter = 0;
+
+        while let Ok(1) = stream.read(&mut buf) {
+            let byte = buf[0];
+            head.push(byte);
+
+            counter = match (counter, byte) {
+                (0, b'\r') => 1,
+                (1, b'\n') => 2,
+                (2, b'\r') => 3,
+                (3, b'\n') => break,
+                _ => 0,
+            };
+
+            if head.len() > MAX_HEAD_SIZE {
+                let error = ControlError::new("headers_too_large");
+                return respond(&mut stream, status(&error), control::make_reply(None, None, Err(error)));
+            }
+        }
+
+        head.truncate(head.len().checked_sub(4)?);
+    }
+
+    let head_str = String::from_utf8(head).ok()?;
+    let mut head_lines = head_str.split("\r\n");
+
+    let status_seq: Vec<&str> = head_lines.next()?.split(' ').collect();
+    let (method, path) = (*status_seq.first()?, *status_seq.get(1)?);
+    let path = path.split('?').next()?;
+
+    let headers: Vec<(&str, &str)> = head_lines
+        .filter_map(|l| l.split_once(": "))
+        .collect();
+
+    let header = |name: &str| headers.iter()
+        .find(|o| o.0.eq_ignore_ascii_case(name))
+        .map(|o| o.1);
+
+    let content_length = header("content-length")
+        .and_then(|o| o.parse().ok())
+        .unwrap_or(0usize);
+
+    // Body is read only for authorized requests of limited size
+    let result = authorize(&config, addr, method, path, header("authorization")).and_then(|endpoint| {
+        if content_length > MAX_BODY_SIZE {
+            return Err(ControlError::new("body_too_large"));
+        }
+
+        let mut body = vec![0; content_length];
+        stream.read_exact(&mut body).map_err(|_| ControlError::new("invalid_json"))?;
+
+        handle(config, addr, endpoint, &body)
+    });
+
+    let status = match &result {
+        Ok(_) => "200 OK",
+        Err(error) if error.code == "unauthorized" && header("authorization").is_none() => "401 Unauthorized",
+        Err(error) => status(error)
+    };
+
+    let kind = route(method, path).map(|o| o.0);
+
+    info!("{addr} > {method} admin {path}");
+
+    respond(&mut stream, status, control::make_reply(None, kind, result))
+}
+
+/// Runs admin REST API on `listener`, sharing messages and tokens with websocket control
+pub fn start_server(config: Arc<RwLock<Config>>, listener: TcpListener) -> Option<()> {
+    let host = config.read().ok()?.admin_host.clone()?;
+
+    // Tokens travel in requests, so the websocket certificate protects them too
+    #[cfg(feature = "use-openssl")]
+    let acceptor = match &config.read().ok()?.websocket_ssl {
+        Some(_) => Some(openssl::ssl::SslAcceptor::mozilla_intermediate(openssl::ssl::SslMethod::tls()).ok()?.build()),
+        None => None
+    };
+
+    info!("Admin server runned on {host}");
+
+    while let Some(stream) = upgrade::accept(&listener) {
+        let Ok(stream) = stream else { continue };
+        let Ok(addr) = stream.peer_addr() else { continue };
+
+        let Some(guard) = ControlGuard::new() else {
+            warn!("{addr} > too many control connections");
+            continue
+        };
+
+        thread::spawn({
+            let config = config.clone();
+            #[cfg(feature = "use-openssl")]
+            let acceptor = acceptor.clone();
+
+            move || {
+                let _guard = guard;
+                accept_stream(
+                    config,
+                    stream,
+                    addr,
+                    #[cfg(feature = "use-openssl")]
+                    acceptor
+                )
+            }
+        });
+    }
+
+    Some(())
+}
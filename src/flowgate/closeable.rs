@@ -1,5 +1,8 @@
 use std::net::{Shutdown, TcpStream};
 
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
 #[cfg(feature = "use-openssl")]
 use openssl::ssl::SslStream;
 
@@ -13,9 +16,16 @@ impl Closeable for TcpStream {
     }
 }
 
+#[cfg(unix)]
+impl Closeable for UnixStream {
+    fn close(&self) {
+        let _ = self.shutdown(Shutdown::Both);
+    }
+}
+
 #[cfg(feature = "use-openssl")]
 impl<T: Closeable> Closeable for SslStream<T> {
     fn close(&self) {
         self.get_ref().close();
     }
-}
+}
\ No newline at end of file
@@ -1,11 +1,13 @@
-use std::{fs, net::TcpStream, time::Duration};
+use std::{collections::HashMap, env, fmt, fs, io, iter, net::ToSocketAddrs, path::{Path, PathBuf}, time::Duration};
 
-use serde_yml::{Number, Value};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize};
+use serde_yml::{Mapping, Value};
 use wildcard_ex::is_match_simple;
 
-use super::ssl_cert::SslCert;
+use super::{ssl_cert::SslCert, upstream::{self, AsyncUpstreamStream}};
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(try_from = "SiteFile", into = "SiteFile")]
 pub struct SiteConfig {
     pub domain: String,
     pub host: String,
@@ -13,20 +15,177 @@ pub struct SiteConfig {
     pub enable_keep_alive: bool,
     pub support_keep_alive: bool,
     pub ip_forwarding: IpForwarding,
-    pub replace_host: Option<String>
+    pub replace_host: Option<String>,
+    pub upstream_protocol: UpstreamProtocol,
+    pub upstream_tls_verify: bool,
+    /// Timeout of connecting to the server
+    pub connect_timeout: Option<Duration>,
+    /// Read and write timeout of connections to the server
+    pub upstream_timeout: Option<Duration>,
+    /// Time to wait for the next request on keep-alive client connection
+    pub idle_timeout: Option<Duration>,
+    /// Time limit of the whole request, from its head to the end of the response
+    pub request_timeout: Option<Duration>,
+    /// Max concurrent connections to the server
+    pub max_connections: Option<usize>,
+    /// Max idle keep-alive connections to the server kept for reuse, 0 disables pooling
+    pub pool_max_idle: Option<usize>,
+    /// Time idle connection is kept in the pool
+    pub pool_idle_timeout: Option<Duration>
 }
 
+/// Keys of site in config file
+const SITE_KEYS: [&str; 17] = [
+    "domain", "host", "enable_keep_alive", "support_keep_alive", "ip_forwarding", "replace_host",
+    "upstream_protocol", "upstream_tls_verify", "connect_timeout", "upstream_timeout", "idle_timeout",
+    "request_timeout", "max_connections", "pool_max_idle", "pool_idle_timeout", "ssl_cert", "ssl_key"
+];
+
+const DEFAULT_POOL_MAX_IDLE: usize = 8;
+pub const DEFAULT_POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
 impl SiteConfig {
-    pub fn connect(&self) -> Option<TcpStream> {
-        TcpStream::connect(self.host.clone()).ok()
+    /// Connects to the server from the async proxy core, `upstream_timeout` is applied by the caller
+    pub async fn connect_async(&self) -> Option<AsyncUpstreamStream> {
+        AsyncUpstreamStream::connect_limited(&self.domain, &self.host, self.connect_timeout, self.max_connections).await
+    }
+
+    /// Takes idle connection to the server from the pool
+    pub fn reuse(&self) -> Option<AsyncUpstreamStream> {
+        if !self.support_keep_alive || self.pool_max_idle == Some(0) { return None }
+        AsyncUpstreamStream::reuse(&self.domain, &self.host)
+    }
+
+    /// Puts connection with complete response back to the pool
+    pub fn release(&self, stream: AsyncUpstreamStream) {
+        if !self.support_keep_alive { return }
+
+        stream.release(
+            &self.domain,
+            &self.host,
+            self.pool_max_idle.unwrap_or(DEFAULT_POOL_MAX_IDLE),
+            self.pool_idle_timeout.unwrap_or(DEFAULT_POOL_IDLE_TIMEOUT)
+        );
+    }
+
+    /// Checks site like sites of config file are checked, returns key of the first invalid value
+    pub fn check(self) -> Result<SiteConfig, &'static str> {
+        let mut p = ConfigParser::default();
+        p.site_config(self.into(), "").ok_or_else(|| {
+            let path = p.errors.first().map(|o| o.path.as_str()).unwrap_or_default();
+            SITE_KEYS.into_iter().find(|o| *o == path).unwrap_or("domain")
+        })
+    }
+
+    /// Checks whether the site has `max_connections` open to the server
+    pub fn is_full(&self) -> bool {
+        self.max_connections.is_some_and(|o| upstream::connections(&self.domain) >= o)
+    }
+
+    /// Serializes site on top of `base` mapping, keeping its unknown keys
+    pub fn to_yaml(&self, base: Option<&Mapping>) -> Mapping {
+        let mut map = base.cloned().unwrap_or_default();
+        let Ok(Value::Mapping(site)) = serde_yml::to_value(self) else { return map };
+
+        for key in [
+            "ssl_cert", "ssl_key", "replace_host", "connect_timeout", "upstream_timeout",
+            "idle_timeout", "request_timeout", "max_connections", "pool_max_idle", "pool_idle_timeout"
+        ] {
+            if !site.contains_key(key) {
+                map.remove(key);
+            }
+        }
+
+        for (key, value) in site {
+            map.insert(key, value);
+        }
+
+        map
     }
 }
 
-#[derive(Clone)]
+impl TryFrom<SiteFile> for SiteConfig {
+    type Error = String;
+
+    fn try_from(site: SiteFile) -> Result<Self, Self::Error> {
+        let mut p = ConfigParser::default();
+        p.site_config(site, "").ok_or_else(|| p.message())
+    }
+}
+
+impl From<SiteConfig> for SiteFile {
+    fn from(site: SiteConfig) -> Self {
+        let (ssl_cert, ssl_key) = site.ssl.as_ref()
+            .map(|o| (o.source().0.to_string(), o.source().1.to_string()))
+            .unzip();
+
+        SiteFile {
+            domain: site.domain,
+            host: site.host,
+            enable_keep_alive: site.enable_keep_alive,
+            support_keep_alive: site.support_keep_alive,
+            ip_forwarding: site.ip_forwarding,
+            replace_host: site.replace_host,
+            upstream_protocol: site.upstream_protocol,
+            upstream_tls_verify: site.upstream_tls_verify,
+            connect_timeout: site.connect_timeout.map(|o| o.as_secs()),
+            upstream_timeout: site.upstream_timeout.map(|o| o.as_secs()),
+            idle_timeout: site.idle_timeout.map(|o| o.as_secs()),
+            request_timeout: site.request_timeout.map(|o| o.as_secs()),
+            max_connections: site.max_connections,
+            pool_max_idle: site.pool_max_idle,
+            pool_idle_timeout: site.pool_idle_timeout.map(|o| o.as_secs()),
+            ssl_cert,
+            ssl_key
+        }
+    }
+}
+
+/// Site as it is written in config file
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SiteFile {
+    domain: String,
+    host: String,
+    #[serde(default = "default_true")]
+    enable_keep_alive: bool,
+    #[serde(default = "default_true")]
+    support_keep_alive: bool,
+    #[serde(default = "default_ip_forwarding")]
+    ip_forwarding: IpForwarding,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    replace_host: Option<String>,
+    #[serde(default)]
+    upstream_protocol: UpstreamProtocol,
+    #[serde(default = "default_true")]
+    upstream_tls_verify: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    connect_timeout: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    upstream_timeout: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    idle_timeout: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    request_timeout: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_connections: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pool_max_idle: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pool_idle_timeout: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ssl_cert: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ssl_key: Option<String>
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
 pub enum IpForwarding {
     Simple,
     Header(String),
     Modern,
+    #[default]
     None
 }
 
@@ -37,99 +196,1260 @@ impl IpForwarding {
             "simple" => Some(IpForwarding::Simple),
             "modern" => Some(IpForwarding::Modern),
             "header" => Some(IpForwarding::Header(String::from("X-Real-IP"))),
-            name => if name.starts_with("header:") {
-                Some(IpForwarding::Header(name[7..].to_string()))
-            } else {
-                None
-            }
+            name => name.strip_prefix("header:")
+                .map(|o| IpForwarding::Header(o.to_string()))
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            IpForwarding::None => "none".to_string(),
+            IpForwarding::Simple => "simple".to_string(),
+            IpForwarding::Modern => "modern".to_string(),
+            IpForwarding::Header(header) => format!("header:{header}")
+        }
+    }
+}
+
+impl TryFrom<String> for IpForwarding {
+    type Error = String;
+
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        IpForwarding::from_name(&name).ok_or(format!("invalid value `{name}`"))
+    }
+}
+
+impl From<IpForwarding> for String {
+    fn from(value: IpForwarding) -> Self {
+        value.name()
+    }
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UpstreamProtocol {
+    #[default]
+    Http1,
+    H2c,
+    H2
+}
+
+impl UpstreamProtocol {
+    pub fn from_name(name: &str) -> Option<UpstreamProtocol> {
+        match name {
+            "http1" => Some(UpstreamProtocol::Http1),
+            "h2c" => Some(UpstreamProtocol::H2c),
+            "h2" => Some(UpstreamProtocol::H2),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            UpstreamProtocol::Http1 => "http1",
+            UpstreamProtocol::H2c => "h2c",
+            UpstreamProtocol::H2 => "h2"
         }
     }
 }
 
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ForwardProxyConfig {
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth: Option<String>
+}
+
+impl ForwardProxyConfig {
+    /// Checks `target` (`host:port`) against the allow-list.
+    /// Patterns without a port match any port of the host
+    pub fn is_allowed(&self, target: &str) -> bool {
+        let host = target.rsplit_once(':').map(|o| o.0).unwrap_or(target);
+
+        self.allow.iter().any(|pattern| if pattern.contains(':') {
+            is_match_simple(pattern, target)
+        } else {
+            is_match_simple(pattern, host)
+        })
+    }
+}
+
 #[derive(Clone)]
+pub struct ListenerConfig {
+    pub host: String,
+    pub tls: bool,
+    pub incoming_ip_forwarding: IpForwarding,
+    pub sites: Option<Vec<String>>,
+    /// Accept CONNECT requests and tunnel them, on plain listeners only
+    pub forward_proxy: Option<ForwardProxyConfig>
+}
+
+impl ListenerConfig {
+    /// Checks whether the listener serves `domain`
+    pub fn serves(&self, domain: &str) -> bool {
+        self.sites.as_ref()
+            .map(|o| o.iter().any(|pattern| is_match_simple(pattern, domain)))
+            .unwrap_or(true)
+    }
+}
+
+/// Listener as it is written in config file, with global `incoming_ip_forwarding` by default
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ListenerFile {
+    host: String,
+    #[serde(default)]
+    tls: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    incoming_ip_forwarding: Option<IpForwarding>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sites: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    forward_proxy: Option<ForwardProxyConfig>
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ControlToken {
+    pub token: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub domains: Option<Vec<String>>
+}
+
+impl ControlToken {
+    /// Checks whether the token can manage `domain`
+    pub fn allows(&self, domain: &str) -> bool {
+        self.domains.as_ref()
+            .map(|o| o.iter().any(|pattern| is_match_simple(pattern, domain)))
+            .unwrap_or(true)
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Http2Config {
+    pub max_concurrent_streams: u32,
+    pub initial_window_size: u32,
+    pub connection_window_size: u32
+}
+
+impl Default for Http2Config {
+    fn default() -> Self {
+        Http2Config {
+            max_concurrent_streams: 100,
+            initial_window_size: 65535,
+            connection_window_size: 1048576
+        }
+    }
+}
+
+/// `http2: true` or HTTP/2 settings
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Http2File {
+    Enabled(bool),
+    Settings(Http2Config)
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(try_from = "ConfigFile", into = "ConfigFile")]
 pub struct Config {
+    pub filename: String,
+    /// Included files with sites, from `include` and `sites.d` directory
+    pub includes: Vec<String>,
+    /// Files that sites were read from, by domain. New sites go to main file
+    pub site_files: HashMap<String, String>,
+    pub save_config: bool,
+    /// Directory certificates set through control API are written to
+    pub cert_dir: Option<String>,
     pub sites: Vec<SiteConfig>,
-    pub http_host: String,
-    pub https_host: String,
+    pub listeners: Vec<ListenerConfig>,
     pub threadpool_size: usize,
+    /// Max client connections served at once, others get 503
+    pub connection_limit: Option<usize>,
+    /// Forward bodies between plain TCP sockets with `splice(2)` on Linux
+    pub zero_copy: bool,
     pub connection_timeout: Duration,
+    /// Time to wait for requests in progress on shutdown
+    pub drain_timeout: Duration,
     pub incoming_ip_forwarding: IpForwarding,
-    pub websocket_host: Option<String>
+    pub websocket_host: Option<String>,
+    pub websocket_ssl: Option<SslCert>,
+    pub websocket_tokens: Vec<ControlToken>,
+    pub admin_host: Option<String>,
+    pub health_check_interval: Option<Duration>,
+    pub http2: Option<Http2Config>
+}
+
+/// Config as it is written in config file
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    http_host: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    https_host: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    listeners: Vec<ListenerFile>,
+    #[serde(default = "default_threadpool_size")]
+    threadpool_size: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    connection_limit: Option<usize>,
+    #[serde(default = "default_true")]
+    zero_copy: bool,
+    #[serde(default = "default_connection_timeout")]
+    connection_timeout: u64,
+    #[serde(default = "default_drain_timeout")]
+    drain_timeout: u64,
+    #[serde(default)]
+    incoming_ip_forwarding: IpForwarding,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    websocket_host: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    websocket_ssl_cert: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    websocket_ssl_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    websocket_tokens: Vec<ControlToken>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    admin_host: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    health_check_interval: Option<u64>,
+    #[serde(default)]
+    save_config: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cert_dir: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    forward_proxy: Option<ForwardProxyConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    http2: Option<Http2File>,
+    #[serde(default, deserialize_with = "one_or_many", skip_serializing_if = "Vec::is_empty")]
+    include: Vec<String>,
+    #[serde(default)]
+    sites: Vec<SiteFile>
+}
+
+impl TryFrom<ConfigFile> for Config {
+    type Error = String;
+
+    fn try_from(file: ConfigFile) -> Result<Self, Self::Error> {
+        let mut p = ConfigParser::default();
+        p.config(file).ok_or_else(|| p.message())
+    }
+}
+
+impl From<Config> for ConfigFile {
+    fn from(config: Config) -> Self {
+        let (websocket_ssl_cert, websocket_ssl_key) = config.websocket_ssl.as_ref()
+            .map(|o| (o.source().0.to_string(), o.source().1.to_string()))
+            .unzip();
+
+        ConfigFile {
+            http_host: None,
+            https_host: None,
+            listeners: config.listeners.into_iter().map(|o| ListenerFile {
+                host: o.host,
+                tls: o.tls,
+                incoming_ip_forwarding: Some(o.incoming_ip_forwarding),
+                sites: o.sites,
+                forward_proxy: o.forward_proxy
+            }).collect(),
+            threadpool_size: config.threadpool_size,
+            connection_limit: config.connection_limit,
+            zero_copy: config.zero_copy,
+            connection_timeout: config.connection_timeout.as_secs(),
+            drain_timeout: config.drain_timeout.as_secs(),
+            incoming_ip_forwarding: config.incoming_ip_forwarding,
+            websocket_host: config.websocket_host,
+            websocket_ssl_cert,
+            websocket_ssl_key,
+            websocket_tokens: config.websocket_tokens,
+            admin_host: config.admin_host,
+            health_check_interval: config.health_check_interval.map(|o| o.as_secs()),
+            save_config: config.save_config,
+            cert_dir: config.cert_dir,
+            forward_proxy: None,
+            http2: config.http2.map(Http2File::Settings),
+            include: Vec::new(),
+            sites: config.sites.into_iter().map(SiteFile::from).collect()
+        }
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_ip_forwarding() -> IpForwarding {
+    IpForwarding::Header("X-Real-IP".to_string())
+}
+
+fn default_threadpool_size() -> usize {
+    10
+}
+
+fn default_connection_timeout() -> u64 {
+    10
+}
+
+fn default_drain_timeout() -> u64 {
+    30
+}
+
+/// Reads a string or a list of strings
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>)
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(one) => vec![one],
+        OneOrMany::Many(many) => many
+    })
 }
 
 impl Config {
-    pub fn parse(filename: &str) -> Option<Config> {
-        let file_content = fs::read_to_string(filename).ok()?;
-        let doc = serde_yml::from_str::<Value>(file_content.as_str()).ok()?;
-
-        let http_host = doc["http_host"].as_str()?.to_string();
-        let https_host = doc["https_host"].as_str()?.to_string();
-
-        let threadpool_size = doc.get("threadpool_size")
-            .unwrap_or(&Value::Number(Number::from(10))).as_u64()? as usize;
-        let connection_timeout = Duration::from_secs(doc.get("connection_timeout")
-            .unwrap_or(&Value::Number(Number::from(10))).as_u64()?);
-        let incoming_ip_forwarding = doc.get("incoming_ip_forwarding")
-            .map(|o| o.as_str()).flatten()
-            .map(|o| IpForwarding::from_name(o)).flatten()
-            .unwrap_or(IpForwarding::None);
-        let websocket_host = doc.get("websocket_host").map(|o| o.as_str()).flatten().map(|o| o.to_string());
+    /// Parses config file, collecting all problems found in it.
+    /// Format is chosen by file extension: `.json`, `.toml` or YAML otherwise
+    pub fn parse(filename: &str) -> Result<Config, Vec<ConfigError>> {
+        let (file_content, mut doc) = read_config(filename).map_err(|e| vec![e])?;
+
+        let mut p = ConfigParser::new(filename, &file_content);
+        p.interpolate(&mut doc, "");
+
+        // Sites are read one by one to report problems of all of them
+        let main_sites = doc.as_mapping_mut().and_then(|o| o.remove("sites"));
+
+        let file = p.deserialize::<ConfigFile>(doc, "");
+        let includes = p.includes(file.as_ref().map(|o| o.include.as_slice()).unwrap_or_default(), filename);
 
         let mut sites: Vec<SiteConfig> = Vec::new();
+        let mut site_files = HashMap::new();
 
-        let sites_yaml = doc["sites"].as_sequence()?;
+        match main_sites {
+            None if includes.is_empty() => p.error("", ConfigErrorKind::MissingKey("sites")),
+            None | Some(Value::Null) => {},
+            Some(Value::Sequence(list)) => for (i, s) in list.into_iter().enumerate() {
+                p.site(s, &format!("sites[{i}]"), &mut sites, &mut site_files);
+            },
+            Some(_) => p.error("sites", ConfigErrorKind::Invalid("expected a list".to_string()))
+        }
 
-        for s in sites_yaml {
-            let mut cert: Option<SslCert> = None;
-            let s = s.as_mapping()?;
+        for file in &includes {
+            let (content, mut doc) = match read_config(file) {
+                Ok(o) => o,
+                Err(e) => {
+                    p.errors.push(e);
+                    continue;
+                }
+            };
 
-            if s.contains_key("ssl_cert") && !s.get("ssl_cert")?.is_null() {
-                cert = Some(
-                    SslCert::new(
-                        s.get("ssl_cert")?.as_str()?,
-                        s.get("ssl_key")?.as_str()?,
-                    )?,
-                );
+            let mut ip = ConfigParser::new(file, &content);
+            ip.interpolate(&mut doc, "");
+
+            for (path, s) in ip.site_list(doc) {
+                ip.site(s, &path, &mut sites, &mut site_files);
             }
-            
-            let site = SiteConfig {
-                domain: s.get("domain")?.as_str()?.to_string(),
-                host: s.get("host")?.as_str()?.to_string(),
-                ssl: cert,
-                enable_keep_alive: s.get("enable_keep_alive")
-                    .map(|o| o.as_bool().unwrap())
-                    .unwrap_or(true),
-                support_keep_alive: s.get("support_keep_alive")
-                    .map(|o| o.as_bool().unwrap())
-                    .unwrap_or(true),
-                ip_forwarding: s.get("ip_forwarding")
-                    .map(|o| o.as_str()).flatten()
-                    .map(|o| IpForwarding::from_name(o)).flatten()
-                    .unwrap_or(IpForwarding::Header("X-Real-IP".to_string())),
-                replace_host: s.get("replace_host")
-                    .map(|o| o.as_str()).flatten().map(|o| o.to_string()),
+
+            p.errors.append(&mut ip.errors);
+        }
+
+        let config = file.and_then(|o| p.config(o));
+
+        match config {
+            Some(config) if p.errors.is_empty() => Ok(Config {
+                filename: filename.to_string(),
+                includes,
+                site_files,
+                sites,
+                ..config
+            }),
+            _ => Err(p.errors)
+        }
+    }
+
+    /// Writes current sites back to the files they were read from, keeping other keys and comments.
+    /// Files are replaced atomically through a temporary file
+    pub fn save(&self) -> Option<()> {
+        for file in iter::once(&self.filename).chain(&self.includes) {
+            let sites: Vec<&SiteConfig> = self.sites.iter()
+                .filter(|o| self.site_files.get(&o.domain).unwrap_or(&self.filename) == file)
+                .collect();
+
+            if sites.is_empty() && !self.site_files.values().any(|o| o == file) { continue }
+
+            save_sites(file, &sites)?;
+        }
+
+        Some(())
+    }
+
+    /// Directory for certificates set through control API, `cert_dir` relative to config file or `certs` next to it
+    pub fn cert_dir(&self) -> PathBuf {
+        Path::new(&self.filename).parent()
+            .unwrap_or(Path::new(""))
+            .join(self.cert_dir.as_deref().unwrap_or("certs"))
+    }
+
+    /// Returns domains of sites added, removed and changed compared to `old`
+    pub fn diff_sites(&self, old: &Config) -> (Vec<String>, Vec<String>, Vec<String>) {
+        let find = |config: &Config, domain: &str| config.sites.iter()
+            .find(|o| o.domain == domain)
+            .map(|o| o.to_yaml(None));
+
+        let mut added = Vec::new();
+        let mut changed = Vec::new();
+
+        for site in &self.sites {
+            match find(old, &site.domain) {
+                None => added.push(site.domain.clone()),
+                Some(o) if o != site.to_yaml(None) => changed.push(site.domain.clone()),
+                _ => {}
+            }
+        }
+
+        let removed = old.sites.iter()
+            .filter(|o| find(self, &o.domain).is_none())
+            .map(|o| o.domain.clone())
+            .collect();
+
+        (added, removed, changed)
+    }
+
+    pub fn get_site(&self, domain: &str) -> Option<&SiteConfig> {
+        self.sites.iter().find(|i| is_match_simple(&i.domain, domain))
+    }
+
+    /// Finds site for `domain` among sites served by `listener`
+    pub fn get_listener_site(&self, listener: &ListenerConfig, domain: &str) -> Option<&SiteConfig> {
+        if !listener.serves(domain) { return None }
+        self.get_site(domain)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ConfigErrorKind {
+    Read(String),
+    Syntax(String),
+    MissingKey(&'static str),
+    /// Value doesn't match config model (wrong type, unknown key and such)
+    Invalid(String),
+    InvalidValue(String),
+    /// Domain and file where it was defined first
+    DuplicateDomain(String, String),
+    UnreadableCert(String),
+    MissingVariable(String),
+    UnreadableSecret(String),
+    InvalidCert,
+    InvalidAddress(String)
+}
+
+impl fmt::Display for ConfigErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigErrorKind::Read(e) => write!(f, "cannot read config file: {e}"),
+            ConfigErrorKind::Syntax(e) => write!(f, "invalid syntax: {e}"),
+            ConfigErrorKind::MissingKey(key) => write!(f, "missing key `{key}`"),
+            ConfigErrorKind::Invalid(e) => write!(f, "{e}"),
+            ConfigErrorKind::InvalidValue(value) => write!(f, "invalid value `{value}`"),
+            ConfigErrorKind::DuplicateDomain(domain, file) => write!(f, "duplicate domain `{domain}`, first defined in {file}"),
+            ConfigErrorKind::UnreadableCert(e) => write!(f, "cannot read certificate: {e}"),
+            ConfigErrorKind::MissingVariable(name) => write!(f, "environment variable `{name}` is not set"),
+            ConfigErrorKind::UnreadableSecret(e) => write!(f, "cannot read secret file: {e}"),
+            ConfigErrorKind::InvalidCert => write!(f, "invalid certificate or key"),
+            ConfigErrorKind::InvalidAddress(host) => write!(f, "invalid address `{host}`")
+        }
+    }
+}
+
+/// Problem found in config `file` at `path` (like `sites[0].host`)
+#[derive(Debug, Clone)]
+pub struct ConfigError {
+    pub file: String,
+    pub path: String,
+    pub line: Option<usize>,
+    pub kind: ConfigErrorKind
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.file.is_empty() {
+            write!(f, "{}: ", self.file)?;
+        }
+        if let Some(line) = self.line {
+            write!(f, "line {line}: ")?;
+        }
+        if !self.path.is_empty() {
+            write!(f, "{}: ", self.path)?;
+        }
+        write!(f, "{}", self.kind)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Yaml,
+    Json,
+    Toml
+}
+
+impl Format {
+    fn from_filename(filename: &str) -> Format {
+        match Path::new(filename).extension().and_then(|o| o.to_str()) {
+            Some("json") => Format::Json,
+            Some("toml") => Format::Toml,
+            _ => Format::Yaml
+        }
+    }
+
+    /// Parses document, returning error message and its line
+    fn parse(self, source: &str) -> Result<Value, (String, Option<usize>)> {
+        match self {
+            Format::Yaml => serde_yml::from_str(source)
+                .map_err(|e| (e.to_string(), e.location().map(|o| o.line()))),
+            Format::Json => serde_json::from_str(source)
+                .map_err(|e| (e.to_string(), Some(e.line()))),
+            Format::Toml => toml::from_str(source)
+                .map_err(|e| (e.message().to_string(), e.span().map(|o| source[..o.start].matches('\n').count() + 1)))
+        }
+    }
+
+    fn write(self, doc: &Value) -> Option<String> {
+        match self {
+            Format::Yaml => serde_yml::to_string(doc).ok(),
+            Format::Json => serde_json::to_string_pretty(doc).ok().map(|o| o + "\n"),
+            Format::Toml => toml::to_string(doc).ok()
+        }
+    }
+}
+
+fn read_config(filename: &str) -> Result<(String, Value), ConfigError> {
+    let error = |line, kind| ConfigError { file: filename.to_string(), path: String::new(), line, kind };
+
+    let content = fs::read_to_string(filename)
+        .map_err(|e| error(None, ConfigErrorKind::Read(e.to_string())))?;
+    let doc = Format::from_filename(filename).parse(&content)
+        .map_err(|(e, line)| error(line, ConfigErrorKind::Syntax(e)))?;
+
+    Ok((content, doc))
+}
+
+/// Lists files matching `pattern`, which can have wildcards in file name only.
+/// Missing directory of a wildcard pattern matches nothing
+fn glob(pattern: &Path) -> io::Result<Vec<PathBuf>> {
+    let name = pattern.file_name().and_then(|o| o.to_str()).unwrap_or_default();
+
+    if !name.contains('*') {
+        fs::metadata(pattern)?;
+        return Ok(vec![pattern.to_path_buf()]);
+    }
+
+    let dir = match pattern.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new(".")
+    };
+
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e)
+    };
+
+    let mut files: Vec<PathBuf> = entries
+        .filter_map(|o| o.ok())
+        .filter(|o| o.file_type().is_ok_and(|o| o.is_file()))
+        .filter(|o| o.file_name().to_str().is_some_and(|o| is_match_simple(name, o)))
+        .map(|o| pattern.with_file_name(o.file_name()))
+        .collect();
+    files.sort();
+
+    Ok(files)
+}
+
+/// Returns site mappings of an included file: a list of sites,
+/// mapping with `sites` key or a single site
+fn included_sites(doc: &Value) -> Vec<Mapping> {
+    match doc {
+        Value::Sequence(list) => list.iter().filter_map(|o| o.as_mapping().cloned()).collect(),
+        Value::Mapping(map) if map.contains_key("sites") => map.get("sites")
+            .and_then(|o| o.as_sequence())
+            .map(|o| o.iter().filter_map(|o| o.as_mapping().cloned()).collect())
+            .unwrap_or_default(),
+        Value::Mapping(map) => vec![map.clone()],
+        _ => Vec::new()
+    }
+}
+
+/// Writes `sites` to config file in the shape it already has
+fn save_sites(filename: &str, sites: &[&SiteConfig]) -> Option<()> {
+    let format = Format::from_filename(filename);
+    let file_content = fs::read_to_string(filename).ok()?;
+    let old_doc = format.parse(&file_content).ok()?;
+    let old_sites = included_sites(&old_doc);
+
+    let mut p = ConfigParser::new(filename, &file_content);
+    let mut current = Vec::new();
+
+    let mut resolved = old_doc.clone();
+    p.interpolate(&mut resolved, "");
+
+    if let Some(map) = resolved.as_mapping_mut().filter(|o| !o.contains_key("domain")) {
+        map.retain(|key, _| key == "sites");
+    }
+
+    for (path, s) in p.site_list(resolved) {
+        p.site(s, &path, &mut current, &mut HashMap::new());
+    }
+
+    if current.len() == sites.len() && current.iter().zip(sites).all(|(a, b)| a.to_yaml(None) == b.to_yaml(None)) {
+        return Some(());
+    }
+
+    let values: Vec<Value> = sites.iter()
+        .map(|site| {
+            let base = old_sites.iter()
+                .find(|o| o.get("domain").and_then(|o| o.as_str()) == Some(site.domain.as_str()));
+            let mut map = site.to_yaml(base);
+
+            // Keep `${...}` templates that still resolve to the same value
+            for (key, template) in base.into_iter().flatten() {
+                let Some(template) = template.as_str().filter(|o| o.contains("${")) else { continue };
+
+                if map.get(key).and_then(|o| o.as_str()) == interpolate(template).ok().as_deref() {
+                    map.insert(key.clone(), template.into());
+                }
+            }
+
+            Value::Mapping(map)
+        })
+        .collect();
+
+    let changes: Vec<(&str, Option<Value>)> = sites.iter().zip(&values)
+        .map(|(site, value)| {
+            let unchanged = current.iter()
+                .find(|o| o.domain == site.domain)
+                .is_some_and(|o| o.to_yaml(None) == site.to_yaml(None));
+            (site.domain.as_str(), (!unchanged).then(|| value.clone()))
+        })
+        .collect();
+
+    let sites = values;
+    let mut doc = old_doc;
+
+    // Sites list under `sites:` is replaced in YAML text, so comments and other keys stay
+    let keeps_text = format == Format::Yaml && matches!(&doc, Value::Mapping(map)
+        if map.contains_key("sites") || (!map.contains_key("domain") && !sites.is_empty()));
+
+    match &mut doc {
+        Value::Mapping(map) if map.contains_key("sites") || !map.contains_key("domain") => {
+            if map.contains_key("sites") || !sites.is_empty() {
+                map.insert("sites".into(), Value::Sequence(sites));
+            }
+        },
+        Value::Mapping(_) if sites.len() == 1 => doc = sites[0].clone(),
+        // TOML document can't be a list
+        _ if format == Format::Toml => doc = Value::Mapping(Mapping::from_iter([("sites".into(), Value::Sequence(sites))])),
+        _ => doc = Value::Sequence(sites)
+    }
+
+    let content = match keeps_text.then(|| splice_sites(&file_content, &changes)).flatten() {
+        Some(content) => content,
+        None => format.write(&doc)?
+    };
+
+    // Temporary file is not readable by others until it gets permissions of the config file
+    let temp = format!("{filename}.tmp");
+    write_private(Path::new(&temp), content.as_bytes()).ok()?;
+    fs::set_permissions(&temp, fs::metadata(filename).ok()?.permissions()).ok()?;
+    fs::rename(&temp, filename).ok()
+}
+
+/// Writes file readable only by the owner
+pub fn write_private(path: &Path, content: &[u8]) -> io::Result<()> {
+    let _ = fs::remove_file(path);
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    io::Write::write_all(&mut options.open(path)?, content)
+}
+
+/// Substitutes `${VAR}`, `${VAR:-default}` and `${file:/path}` (file content without
+/// trailing newline) in config string. `$${` is written as `${`
+fn interpolate(text: &str) -> Result<String, ConfigErrorKind> {
+    let mut result = String::new();
+    let mut rest = text;
+
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            result.push_str(&rest[..start - 1]);
+            result.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+
+        result.push_str(&rest[..start]);
+
+        let Some(end) = rest[start..].find('}') else {
+            return Err(ConfigErrorKind::InvalidValue(text.to_string()));
+        };
+        let expr = &rest[start + 2..start + end];
+        rest = &rest[start + end + 1..];
+
+        if let Some(path) = expr.strip_prefix("file:") {
+            let content = fs::read_to_string(path)
+                .map_err(|e| ConfigErrorKind::UnreadableSecret(format!("{path}: {e}")))?;
+            result.push_str(content.trim_end_matches(['\r', '\n']));
+            continue;
+        }
+
+        let (name, default) = match expr.split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (expr, None)
+        };
+
+        // Like in shell, default replaces empty value too
+        let value = env::var(name).ok()
+            .filter(|o| default.is_none() || !o.is_empty())
+            .or(default.map(|o| o.to_string()))
+            .ok_or(ConfigErrorKind::MissingVariable(name.to_string()))?;
+        result.push_str(&value);
+    }
+
+    result.push_str(rest);
+    Ok(result)
+}
+
+fn join_path(path: &str, key: &str) -> String {
+    if path.is_empty() { key.to_string() } else { format!("{path}.{key}") }
+}
+
+/// Finds lines of keys and list items in block-style YAML
+fn key_lines(source: &str) -> HashMap<String, usize> {
+    let mut lines = HashMap::new();
+    let mut stack: Vec<(usize, String)> = Vec::new();
+    let mut items: HashMap<String, usize> = HashMap::new();
+    let mut block: Option<usize> = None;
+
+    for (number, line) in source.lines().enumerate() {
+        let number = number + 1;
+        let mut indent = line.len() - line.trim_start().len();
+        let mut content = line.trim();
+
+        if content.is_empty() || content.starts_with('#') { continue }
+
+        if let Some(block_indent) = block {
+            if indent > block_indent { continue }
+            block = None;
+        }
+
+        if let Some(item) = content.strip_prefix("- ").or((content == "-").then_some("")) {
+            while stack.last().is_some_and(|o| o.0 > indent) { stack.pop(); }
+
+            let parent = stack.last().map(|o| o.1.clone()).unwrap_or_default();
+            let index = items.entry(parent.clone()).or_insert(0);
+            let path = format!("{parent}[{index}]");
+            *index += 1;
+
+            lines.insert(path.clone(), number);
+            stack.push((indent + 1, path));
+
+            indent += 2;
+            content = item.trim();
+            if content.is_empty() { continue }
+        } else {
+            while stack.last().is_some_and(|o| o.0 >= indent) { stack.pop(); }
+        }
+
+        let key = match content.split_once(": ") {
+            Some((key, _)) => key,
+            None => match content.strip_suffix(':') {
+                Some(key) => key,
+                None => continue
+            }
+        };
+        let key = key.trim().trim_matches('"').trim_matches('\'');
+
+        let parent = stack.last().map(|o| o.1.clone()).unwrap_or_default();
+        let path = join_path(&parent, key);
+
+        lines.insert(path.clone(), number);
+        stack.push((indent, path));
+
+        let value = content[key.len()..].trim_start_matches(['"', '\'', ':', ' ']);
+        if value.starts_with('|') || value.starts_with('>') {
+            block = Some(indent);
+        }
+    }
+
+    lines
+}
+
+/// Collects config problems instead of stopping at the first one
+#[derive(Default)]
+struct ConfigParser {
+    file: String,
+    lines: HashMap<String, usize>,
+    /// Paths of values that failed interpolation, other problems with them are not reported
+    unresolved: Vec<String>,
+    errors: Vec<ConfigError>
+}
+
+impl ConfigParser {
+    fn new(file: &str, source: &str) -> ConfigParser {
+        ConfigParser {
+            file: file.to_string(),
+            // Lines are only known for YAML files
+            lines: match Format::from_filename(file) {
+                Format::Yaml => key_lines(source),
+                _ => HashMap::new()
+            },
+            ..Default::default()
+        }
+    }
+
+    fn error(&mut self, path: &str, kind: ConfigErrorKind) {
+        if self.unresolved.iter().any(|o| o == path) { return }
+
+        let mut lookup = path;
+        let line = loop {
+            if let Some(line) = self.lines.get(lookup) { break Some(*line) }
+            match lookup.rfind(['.', '[']) {
+                Some(pos) => lookup = &lookup[..pos],
+                None => break None
+            }
+        };
+
+        self.errors.push(ConfigError { file: self.file.clone(), path: path.to_string(), line, kind });
+    }
+
+    /// Joins all problems into one message
+    fn message(&self) -> String {
+        self.errors.iter().map(|o| o.to_string()).collect::<Vec<String>>().join("; ")
+    }
+
+    /// Substitutes variables in all string values of `value`
+    fn interpolate(&mut self, value: &mut Value, path: &str) {
+        match value {
+            Value::String(text) if text.contains("${") => match interpolate(text) {
+                Ok(result) => *text = result,
+                Err(kind) => {
+                    self.error(path, kind);
+                    self.unresolved.push(path.to_string());
+                }
+            },
+            Value::Sequence(list) => for (i, item) in list.iter_mut().enumerate() {
+                self.interpolate(item, &format!("{path}[{i}]"));
+            },
+            Value::Mapping(map) => for (key, item) in map.iter_mut() {
+                let path = join_path(path, key.as_str().unwrap_or_default());
+                self.interpolate(item, &path);
+            },
+            _ => {}
+        }
+    }
+
+    /// Deserializes `value` found at `path` into config model.
+    /// Keys with problems are dropped one by one to find problems in other keys
+    fn deserialize<T: DeserializeOwned>(&mut self, mut value: Value, path: &str) -> Option<T> {
+        let mut failed = false;
+
+        loop {
+            let e = match serde_path_to_error::deserialize(value.clone()) {
+                Ok(value) if !failed => return Some(value),
+                Ok(_) => return None,
+                Err(e) => e
             };
 
+            let inner = e.path().to_string();
+            let inner = if inner == "." { "" } else { inner.as_str() };
+            let key = inner.split(['.', '[']).next().unwrap_or_default().to_string();
+
+            // Missing keys after dropping some are not real problems
+            if key.is_empty() && failed { return None }
+
+            let full_path = match (path.is_empty(), inner.starts_with('[')) {
+                (true, _) => inner.to_string(),
+                (false, true) => format!("{path}{inner}"),
+                (false, false) => join_path(path, inner)
+            };
+
+            self.error(full_path.trim_end_matches('.'), ConfigErrorKind::Invalid(e.into_inner().to_string()));
+            failed = true;
+
+            let removed = value.as_mapping_mut().and_then(|o| o.remove(&key));
+            if key.is_empty() || removed.is_none() { return None }
+        }
+    }
+
+    /// Expands `include` patterns (relative to config directory) and `sites.d` files
+    fn includes(&mut self, patterns: &[String], filename: &str) -> Vec<String> {
+        let dir = Path::new(filename).parent().unwrap_or(Path::new(""));
+
+        let patterns = patterns.iter().cloned()
+            .chain(["yml", "yaml", "json", "toml"].map(|o| format!("sites.d/*.{o}")));
+
+        let mut files: Vec<String> = Vec::new();
+
+        for pattern in patterns {
+            match glob(&dir.join(&pattern)) {
+                Ok(list) => for file in list {
+                    let file = file.to_string_lossy().to_string();
+                    if file != filename && !files.contains(&file) {
+                        files.push(file);
+                    }
+                },
+                Err(e) => self.error("include", ConfigErrorKind::Read(format!("{pattern}: {e}")))
+            }
+        }
+
+        files
+    }
+
+    /// Returns sites of an included file with their paths
+    fn site_list(&mut self, doc: Value) -> Vec<(String, Value)> {
+        match doc {
+            Value::Null => Vec::new(),
+            Value::Sequence(list) => list.into_iter().enumerate().map(|(i, s)| (format!("[{i}]"), s)).collect(),
+            Value::Mapping(mut map) if map.contains_key("sites") => {
+                let sites = map.remove("sites");
+
+                if let Some(key) = map.keys().next() {
+                    let key = key.as_str().unwrap_or_default().to_string();
+                    self.error(&key, ConfigErrorKind::Invalid("unknown key, expected `sites`".to_string()));
+                }
+
+                match sites {
+                    Some(Value::Sequence(list)) => list.into_iter().enumerate()
+                        .map(|(i, s)| (format!("sites[{i}]"), s))
+                        .collect(),
+                    _ => {
+                        self.error("sites", ConfigErrorKind::Invalid("expected a list".to_string()));
+                        Vec::new()
+                    }
+                }
+            },
+            doc => vec![(String::new(), doc)]
+        }
+    }
+
+    /// Reads site at `path` and adds it to `sites`
+    fn site(&mut self, value: Value, path: &str, sites: &mut Vec<SiteConfig>, site_files: &mut HashMap<String, String>) {
+        if let Some(site) = self.deserialize::<SiteFile>(value, path) {
+            self.add_site(site, path, sites, site_files);
+        }
+    }
+
+    /// Checks site and adds it to `sites`, reporting domains defined before
+    fn add_site(&mut self, site: SiteFile, path: &str, sites: &mut Vec<SiteConfig>, site_files: &mut HashMap<String, String>) {
+        if let Some(file) = site_files.get(&site.domain) {
+            self.error(&join_path(path, "domain"), ConfigErrorKind::DuplicateDomain(site.domain.clone(), file.clone()));
+        } else {
+            site_files.insert(site.domain.clone(), self.file.clone());
+        }
+
+        if let Some(site) = self.site_config(site, path) {
             sites.push(site);
         }
+    }
+
+    fn site_config(&mut self, site: SiteFile, path: &str) -> Option<SiteConfig> {
+        let errors = self.errors.len();
+
+        if site.domain.is_empty() || site.domain.contains(|c: char| c.is_whitespace() || c.is_control() || c == '/') {
+            self.error(&join_path(path, "domain"), ConfigErrorKind::InvalidValue(site.domain.clone()));
+        }
+
+        // Address prefix is sent once per connection, while HTTP/2 sessions are shared by clients
+        if matches!(site.ip_forwarding, IpForwarding::Simple | IpForwarding::Modern)
+            && site.upstream_protocol != UpstreamProtocol::Http1 {
+            self.error(&join_path(path, "ip_forwarding"), ConfigErrorKind::Invalid(
+                format!("`{}` needs `upstream_protocol: http1`", site.ip_forwarding.name())
+            ));
+        }
+
+        if !site.host.starts_with("unix:")
+            && site.host.rsplit_once(':').and_then(|o| o.1.parse::<u16>().ok()).is_none() {
+            self.error(&join_path(path, "host"), ConfigErrorKind::InvalidAddress(site.host.clone()));
+        }
+
+        let ssl = self.cert(path, ("ssl_cert", "ssl_key"), &site.ssl_cert, &site.ssl_key);
+
+        for (key, value) in [
+            ("connect_timeout", site.connect_timeout),
+            ("upstream_timeout", site.upstream_timeout),
+            ("idle_timeout", site.idle_timeout),
+            ("request_timeout", site.request_timeout),
+            ("max_connections", site.max_connections.map(|o| o as u64)),
+            ("pool_idle_timeout", site.pool_idle_timeout)
+        ] {
+            if value == Some(0) {
+                self.error(&join_path(path, key), ConfigErrorKind::InvalidValue("0".to_string()));
+            }
+        }
+
+        if self.errors.len() > errors { return None }
+
+        Some(SiteConfig {
+            domain: site.domain,
+            host: site.host,
+            ssl,
+            enable_keep_alive: site.enable_keep_alive,
+            support_keep_alive: site.support_keep_alive,
+            ip_forwarding: site.ip_forwarding,
+            replace_host: site.replace_host,
+            upstream_protocol: site.upstream_protocol,
+            upstream_tls_verify: site.upstream_tls_verify,
+            connect_timeout: site.connect_timeout.map(Duration::from_secs),
+            upstream_timeout: site.upstream_timeout.map(Duration::from_secs),
+            idle_timeout: site.idle_timeout.map(Duration::from_secs),
+            request_timeout: site.request_timeout.map(Duration::from_secs),
+            max_connections: site.max_connections,
+            pool_max_idle: site.pool_max_idle,
+            pool_idle_timeout: site.pool_idle_timeout.map(Duration::from_secs)
+        })
+    }
+
+    fn config(&mut self, file: ConfigFile) -> Option<Config> {
+        let errors = self.errors.len();
+
+        let mut listeners = Vec::new();
+
+        for (host, tls) in [(file.http_host, false), (file.https_host, true)] {
+            let path = if tls { "https_host" } else { "http_host" };
+
+            if let Some(host) = self.address(path, host) {
+                listeners.push(ListenerConfig {
+                    host,
+                    tls,
+                    incoming_ip_forwarding: file.incoming_ip_forwarding.clone(),
+                    sites: None,
+                    // Top-level `forward_proxy` is for `http_host`
+                    forward_proxy: if tls { None } else { file.forward_proxy.clone() }
+                });
+            }
+        }
+
+        for (i, l) in file.listeners.into_iter().enumerate() {
+            if l.tls && l.forward_proxy.is_some() {
+                self.error(&format!("listeners[{i}].forward_proxy"), ConfigErrorKind::Invalid("forward proxy needs a listener without tls".to_string()));
+            }
+
+            listeners.push(ListenerConfig {
+                host: self.address(&format!("listeners[{i}].host"), Some(l.host)).unwrap_or_default(),
+                tls: l.tls,
+                incoming_ip_forwarding: l.incoming_ip_forwarding.unwrap_or(file.incoming_ip_forwarding.clone()),
+                sites: l.sites,
+                forward_proxy: l.forward_proxy
+            });
+        }
+
+        let http2 = match file.http2 {
+            None | Some(Http2File::Enabled(false)) => None,
+            Some(Http2File::Enabled(true)) => Some(Http2Config::default()),
+            Some(Http2File::Settings(http2)) => {
+                for (key, size) in [
+                    ("initial_window_size", http2.initial_window_size),
+                    ("connection_window_size", http2.connection_window_size)
+                ] {
+                    if size > i32::MAX as u32 {
+                        self.error(&join_path("http2", key), ConfigErrorKind::InvalidValue(size.to_string()));
+                    }
+                }
+                Some(http2)
+            }
+        };
+
+        if file.threadpool_size == 0 {
+            self.error("threadpool_size", ConfigErrorKind::InvalidValue("0".to_string()));
+        }
+
+        let mut sites = Vec::new();
+        let mut site_files = HashMap::new();
 
-        Some(Config {
+        for (i, site) in file.sites.into_iter().enumerate() {
+            self.add_site(site, &format!("sites[{i}]"), &mut sites, &mut site_files);
+        }
+
+        let config = Config {
+            filename: String::new(),
+            includes: Vec::new(),
+            site_files,
+            save_config: file.save_config,
+            cert_dir: file.cert_dir,
             sites,
-            http_host,
-            https_host,
-            threadpool_size,
-            connection_timeout,
-            incoming_ip_forwarding,
-            websocket_host
-        }.clone())
+            listeners,
+            threadpool_size: file.threadpool_size,
+            connection_limit: file.connection_limit,
+            zero_copy: file.zero_copy,
+            connection_timeout: Duration::from_secs(file.connection_timeout),
+            drain_timeout: Duration::from_secs(file.drain_timeout),
+            incoming_ip_forwarding: file.incoming_ip_forwarding,
+            websocket_host: self.address("websocket_host", file.websocket_host),
+            websocket_ssl: self.cert("", ("websocket_ssl_cert", "websocket_ssl_key"), &file.websocket_ssl_cert, &file.websocket_ssl_key),
+            websocket_tokens: file.websocket_tokens,
+            admin_host: self.address("admin_host", file.admin_host),
+            health_check_interval: file.health_check_interval.map(Duration::from_secs),
+            http2
+        };
+
+        (self.errors.len() == errors).then_some(config)
     }
 
-    pub fn get_site(&self, domain: &str) -> Option<&SiteConfig> {
-        for i in &self.sites {
-            if is_match_simple(&i.domain, domain) {
-                return Some(i);
+    /// Checks that host to listen on resolves
+    fn address(&mut self, path: &str, host: Option<String>) -> Option<String> {
+        let host = host?;
+
+        if host.to_socket_addrs().is_err() {
+            self.error(path, ConfigErrorKind::InvalidAddress(host.clone()));
+        }
+
+        Some(host)
+    }
+
+    fn cert(
+        &mut self,
+        path: &str,
+        (cert_key, key_key): (&'static str, &'static str),
+        cert: &Option<String>,
+        key: &Option<String>
+    ) -> Option<SslCert> {
+        let cert = cert.as_ref()?;
+        let Some(key) = key else {
+            self.error(path, ConfigErrorKind::MissingKey(key_key));
+            return None;
+        };
+
+        for (name, source) in [(cert_key, cert), (key_key, key)] {
+            if source.trim_start().starts_with("-----BEGIN") { continue }
+
+            if let Err(e) = fs::read(source) {
+                self.error(&join_path(path, name), ConfigErrorKind::UnreadableCert(format!("{source}: {e}")));
+                return None;
             }
         }
-        return None;
+
+        let ssl = SslCert::new(cert, key);
+        if ssl.is_none() {
+            self.error(&join_path(path, cert_key), ConfigErrorKind::InvalidCert);
+        }
+        ssl
+    }
+}
+
+/// Replaces block list under top-level `sites:` key in YAML `text`, keeping the rest of the text.
+/// Sites are given by domain, with `None` for unchanged ones that keep their text and comments.
+/// Returns `None` if the list is not in block style or an unchanged site is not found
+fn splice_sites(text: &str, sites: &[(&str, Option<Value>)]) -> Option<String> {
+    let lines: Vec<&str> = text.split_inclusive('\n').collect();
+
+    let start = lines.iter().position(|o| o.strip_prefix("sites:")
+        .is_some_and(|o| o.trim().is_empty() || o.trim().starts_with('#')));
+
+    let (head, mut end) = match start {
+        Some(start) => (start + 1, start + 1),
+        None => (lines.len(), lines.len())
+    };
+    while end < lines.len() && (lines[end].trim().is_empty() || lines[end].starts_with([' ', '-', '#'])) {
+        end += 1;
+    }
+    // Blank lines and comments after the list belong to the next key
+    while end > head && (lines[end - 1].trim().is_empty() || lines[end - 1].starts_with('#')) {
+        end -= 1;
+    }
+
+    let item_start = |o: &str| o.trim_start().starts_with("- ") || o.trim() == "-";
+    let indent = lines[head..end].iter().find(|o| item_start(o))
+        .map_or(2, |o| o.len() - o.trim_start().len());
+
+    // Text of existing items by domain, lines before the first item stay in place
+    let mut prefix = String::new();
+    let mut items: Vec<(Option<String>, String)> = Vec::new();
+    for line in &lines[head..end] {
+        if item_start(line) && line.len() - line.trim_start().len() == indent {
+            items.push((None, String::new()));
+        }
+        match items.last_mut() {
+            Some((_, item)) => item.push_str(line),
+            None => prefix.push_str(line)
+        }
+    }
+    for (domain, item) in &mut items {
+        *domain = serde_yml::from_str::<Value>(item).ok()
+            .and_then(|o| o.get(0)?.get("domain")?.as_str().map(|o| o.to_string()));
+    }
+
+    let mut list = prefix;
+    for (domain, site) in sites {
+        match site {
+            Some(site) => {
+                let item = serde_yml::to_string(&Value::Sequence(vec![site.clone()])).ok()?;
+                for line in item.lines() {
+                    list.push_str(&format!("{}{line}\n", " ".repeat(indent)));
+                }
+            },
+            None => list.push_str(&items.iter().find(|o| o.0.as_deref() == Some(*domain))?.1)
+        }
+    }
+    if !list.is_empty() && !list.ends_with('\n') {
+        list.push('\n');
+    }
+
+    let mut result: String = lines[..head].concat();
+    if start.is_none() {
+        if !result.is_empty() && !result.ends_with('\n') {
+            result.push('\n');
+        }
+        result.push_str("sites:\n");
+    }
+    result.push_str(&list);
+    result.push_str(&lines[end..].concat());
+    Some(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interpolate_variables() {
+        env::set_var("FLOWGATE_TEST_SET", "value");
+        env::set_var("FLOWGATE_TEST_EMPTY", "");
+        env::remove_var("FLOWGATE_TEST_UNSET");
+
+        assert_eq!(interpolate("a ${FLOWGATE_TEST_SET} b").unwrap(), "a value b");
+        assert_eq!(interpolate("${FLOWGATE_TEST_EMPTY}").unwrap(), "");
+        assert_eq!(interpolate("${FLOWGATE_TEST_EMPTY:-default}").unwrap(), "default");
+        assert_eq!(interpolate("${FLOWGATE_TEST_SET:-default}").unwrap(), "value");
+        assert_eq!(interpolate("${FLOWGATE_TEST_UNSET:-}").unwrap(), "");
+        assert!(matches!(interpolate("${FLOWGATE_TEST_UNSET}"), Err(ConfigErrorKind::MissingVariable(o)) if o == "FLOWGATE_TEST_UNSET"));
+        assert!(matches!(interpolate("${FLOWGATE_TEST_SET"), Err(ConfigErrorKind::InvalidValue(_))));
+    }
+
+    #[test]
+    fn interpolate_escape() {
+        assert_eq!(interpolate("$${FLOWGATE_TEST_UNSET}").unwrap(), "${FLOWGATE_TEST_UNSET}");
+        assert_eq!(interpolate("cost $5, $${x}").unwrap(), "cost $5, ${x}");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn interpolate_files() {
+        let path = env::temp_dir().join(format!("flowgate-test-secret-{}", std::process::id()));
+        fs::write(&path, "secret\n").unwrap();
+
+        let result = interpolate(&format!("key: ${{file:{}}}", path.display()));
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(result.unwrap(), "key: secret");
+        assert!(matches!(interpolate("${file:/nonexistent/flowgate}"), Err(ConfigErrorKind::UnreadableSecret(_))));
+    }
+}
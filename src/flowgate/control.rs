@@ -0,0 +1,499 @@
+use std::{collections::HashMap, fs, net::SocketAddr, path::{Component, Path}, sync::{atomic::{AtomicUsize, Ordering}, mpsc::Sender, Arc, Mutex, RwLock}, thread, time::Duration};
+
+use log::{info, warn};
+use serde_json::{json, Map, Value};
+
+use super::{closeable::Closeable, config::{write_private, Config, ControlToken, IpForwarding, SiteConfig, UpstreamProtocol}, ssl_cert::{is_pem, SslCert}, stats::STATS, upstream::{self, UpstreamStream}};
+
+fn site_json(site: &SiteConfig) -> Value {
+    json!({
+        "domain": site.domain,
+        "host": site.host,
+        "ssl": site.ssl.is_some(),
+        "enable_keep_alive": site.enable_keep_alive,
+        "support_keep_alive": site.support_keep_alive,
+        "ip_forwarding": site.ip_forwarding.name(),
+        "replace_host": site.replace_host,
+        "upstream_protocol": site.upstream_protocol.name(),
+        "upstream_tls_verify": site.upstream_tls_verify,
+        "connect_timeout": site.connect_timeout.map(|o| o.as_secs()),
+        "upstream_timeout": site.upstream_timeout.map(|o| o.as_secs()),
+        "idle_timeout": site.idle_timeout.map(|o| o.as_secs()),
+        "request_timeout": site.request_timeout.map(|o| o.as_secs()),
+        "max_connections": site.max_connections,
+        "pool_max_idle": site.pool_max_idle,
+        "pool_idle_timeout": site.pool_idle_timeout.map(|o| o.as_secs())
+    })
+}
+
+fn config_json(config: &Config) -> Value {
+    json!({
+        "listeners": config.listeners.iter().map(|o| json!({
+            "host": o.host,
+            "tls": o.tls,
+            "incoming_ip_forwarding": o.incoming_ip_forwarding.name(),
+            "sites": o.sites,
+            "forward_proxy": o.forward_proxy.as_ref().map(|o| json!({
+                "allow": o.allow,
+                "auth": o.auth.is_some()
+            }))
+        })).collect::<Vec<Value>>(),
+        "threadpool_size": config.threadpool_size,
+        "connection_limit": config.connection_limit,
+        "zero_copy": config.zero_copy,
+        "connection_timeout": config.connection_timeout.as_secs(),
+        "drain_timeout": config.drain_timeout.as_secs(),
+        "incoming_ip_forwarding": config.incoming_ip_forwarding.name(),
+        "websocket_host": config.websocket_host,
+        "admin_host": config.admin_host,
+        "health_check_interval": config.health_check_interval.map(|o| o.as_secs()),
+        "http2": config.http2.as_ref().map(|o| json!({
+            "max_concurrent_streams": o.max_concurrent_streams,
+            "initial_window_size": o.initial_window_size,
+            "connection_window_size": o.connection_window_size
+        })),
+        "sites": config.sites.iter().map(site_json).collect::<Vec<Value>>()
+    })
+}
+
+/// Event channels of subscribed control clients
+static SUBSCRIBERS: Mutex<Vec<(Sender<Value>, ControlToken)>> = Mutex::new(Vec::new());
+
+pub fn subscribe(events: Sender<Value>, token: ControlToken) {
+    if let Ok(mut subscribers) = SUBSCRIBERS.lock() {
+        subscribers.push((events, token));
+    }
+}
+
+/// Sends event to subscribed clients allowed to see its `domain`
+pub fn notify(event: Value) {
+    let domain = event.get("domain").and_then(|o| o.as_str()).map(|o| o.to_string());
+
+    if let Ok(mut subscribers) = SUBSCRIBERS.lock() {
+        subscribers.retain(|(events, token)| {
+            if domain.as_ref().is_some_and(|o| !token.allows(o)) { return true }
+            events.send(event.clone()).is_ok()
+        });
+    }
+}
+
+/// Periodically connects to every site server and notifies about state changes
+pub fn run_health_checks(config: Arc<RwLock<Config>>, interval: Duration) -> Option<()> {
+    let mut states: HashMap<String, bool> = HashMap::new();
+
+    loop {
+        let sites = config.read().ok()?.sites.clone();
+
+        for site in sites {
+            let up = UpstreamStream::connect(&site.host, site.connect_timeout).map(|o| o.close()).is_some();
+
+            if states.insert(site.domain.clone(), up).is_some_and(|o| o != up) {
+                info!("{} server is {}", site.domain, if up { "up" } else { "down" });
+                notify(json!({
+                    "type": "event",
+                    "event": if up { "upstream_up" } else { "upstream_down" },
+                    "domain": site.domain,
+                    "host": site.host
+                }));
+            }
+        }
+
+        thread::sleep(interval);
+    }
+}
+
+/// Parses config file again and replaces current config if it is valid.
+/// Connections in progress keep their sites, listeners need restart to change
+pub fn reload_config(config: &Arc<RwLock<Config>>) -> Result<(), ControlError> {
+    let filename = config.read().map_err(internal)?.filename.clone();
+
+    let new_config = match Config::parse(&filename) {
+        Ok(config) => config,
+        Err(errors) => {
+            for error in errors {
+                warn!("{error}");
+            }
+            warn!("Config {filename} is invalid, keeping current config");
+            return Err(ControlError::new("invalid_config"));
+        }
+    };
+
+    let mut conf = config.write().map_err(internal)?;
+
+    let (added, removed, changed) = new_config.diff_sites(&conf);
+
+    let listeners = |c: &Config| c.listeners.iter().map(|o| o.host.clone()).collect::<Vec<String>>();
+    if listeners(&conf) != listeners(&new_config) {
+        warn!("Listeners changes will be applied after restart");
+    }
+
+    *conf = new_config;
+
+    info!("Config reloaded (added: {:?}, removed: {:?}, changed: {:?})", added, removed, changed);
+    notify(json!({ "type": "event", "event": "config_reloaded" }));
+
+    Ok(())
+}
+
+/// Max concurrent control connections of websocket and admin servers, others are closed right away
+const MAX_CONNECTIONS: usize = 64;
+
+static CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Holds a place among [`MAX_CONNECTIONS`] while the control connection is served
+pub struct ControlGuard;
+
+impl ControlGuard {
+    pub fn new() -> Option<ControlGuard> {
+        CONNECTIONS.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |o| (o < MAX_CONNECTIONS).then_some(o + 1)).ok()?;
+        Some(ControlGuard)
+    }
+}
+
+impl Drop for ControlGuard {
+    fn drop(&mut self) {
+        CONNECTIONS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Machine-readable error of control message
+pub struct ControlError {
+    pub code: &'static str,
+    pub field: Option<&'static str>
+}
+
+impl ControlError {
+    pub fn new(code: &'static str) -> ControlError {
+        ControlError { code, field: None }
+    }
+
+    pub fn field(code: &'static str, field: &'static str) -> ControlError {
+        ControlError { code, field: Some(field) }
+    }
+}
+
+pub type Message = Map<String, Value>;
+
+fn get_field<'a>(data: &'a Message, field: &'static str) -> Result<&'a Value, ControlError> {
+    data.get(field).ok_or(ControlError::field("missing_field", field))
+}
+
+pub fn get_str<'a>(data: &'a Message, field: &'static str) -> Result<&'a str, ControlError> {
+    get_field(data, field)?.as_str().ok_or(ControlError::field("invalid_field", field))
+}
+
+fn get_bool(data: &Message, field: &'static str) -> Result<bool, ControlError> {
+    get_field(data, field)?.as_bool().ok_or(ControlError::field("invalid_field", field))
+}
+
+/// Returns `None` if the field is missing or null
+fn get_opt_str<'a>(data: &'a Message, field: &'static str) -> Result<Option<&'a str>, ControlError> {
+    match data.get(field) {
+        None | Some(Value::Null) => Ok(None),
+        Some(o) => o.as_str().map(Some).ok_or(ControlError::field("invalid_field", field))
+    }
+}
+
+/// Returns `None` if the field is missing or null, zero is not allowed
+fn get_opt_number(data: &Message, field: &'static str) -> Result<Option<u64>, ControlError> {
+    match data.get(field) {
+        None | Some(Value::Null) => Ok(None),
+        Some(o) => o.as_u64().filter(|o| *o > 0).map(Some).ok_or(ControlError::field("invalid_field", field))
+    }
+}
+
+/// Reads `ssl_cert` and `ssl_key` (file paths or inline PEM) for `domain`.
+/// Inline PEM is written to files in `cert_dir`, so keys don't get into config file.
+/// Tokens limited to domains can only give paths relative to `cert_dir`.
+/// Returns `None` if certificate is not given and `Some(None)` if it is null
+fn get_cert(config: &Arc<RwLock<Config>>, token: &ControlToken, domain: &str, data: &Message) -> Result<Option<Option<SslCert>>, ControlError> {
+    let Some(cert) = data.get("ssl_cert") else { return Ok(None) };
+    if cert.is_null() { return Ok(Some(None)) }
+
+    let cert = cert.as_str().ok_or(ControlError::field("invalid_field", "ssl_cert"))?;
+    let key = get_str(data, "ssl_key")?;
+    let cert_dir = config.read().map_err(internal)?.cert_dir();
+
+    let mut sources = Vec::new();
+    for (field, source) in [("ssl_cert", cert), ("ssl_key", key)] {
+        if is_pem(source) || token.domains.is_none() {
+            sources.push(source.to_string());
+        } else if Path::new(source).components().all(|o| matches!(o, Component::Normal(_))) {
+            sources.push(cert_dir.join(source).to_string_lossy().to_string());
+        } else {
+            return Err(ControlError::field("invalid_field", field));
+        }
+    }
+
+    SslCert::new(&sources[0], &sources[1]).ok_or(ControlError::field("invalid_certificate", "ssl_cert"))?;
+
+    let name: String = domain.chars()
+        .map(|o| if o.is_ascii_alphanumeric() || ".-_".contains(o) { o } else { '_' })
+        .collect();
+
+    for ((field, extension), source) in [("ssl_cert", "crt"), ("ssl_key", "key")].into_iter().zip(&mut sources) {
+        if !is_pem(source) { continue }
+
+        let path = cert_dir.join(format!("{name}.{extension}"));
+        fs::create_dir_all(&cert_dir)
+            .and_then(|_| write_private(&path, source.as_bytes()))
+            .map_err(|_| ControlError::field("save_failed", field))?;
+        *source = path.to_string_lossy().to_string();
+    }
+
+    SslCert::new(&sources[0], &sources[1])
+        .map(|o| Some(Some(o)))
+        .ok_or(ControlError::field("invalid_certificate", "ssl_cert"))
+}
+
+pub fn unauthorized(kind: &str, addr: SocketAddr) -> ControlError {
+    warn!("{addr} > unauthorized control message {kind}");
+    ControlError::new("unauthorized")
+}
+
+/// Builds reply to the message with optional `id`
+pub fn make_reply(id: Option<&Value>, kind: Option<&str>, result: Result<Value, ControlError>) -> Value {
+    let mut reply = Map::new();
+
+    if let Some(id) = id {
+        reply.insert("id".to_string(), id.clone());
+    }
+    if let Some(kind) = kind {
+        reply.insert("type".to_string(), json!(kind));
+    }
+
+    match result {
+        Ok(Value::Object(payload)) => {
+            reply.insert("ok".to_string(), json!(true));
+            reply.extend(payload);
+        },
+        Ok(_) => {
+            reply.insert("ok".to_string(), json!(true));
+        },
+        Err(error) => {
+            reply.insert("ok".to_string(), json!(false));
+            reply.insert("error".to_string(), json!(error.code));
+            if let Some(field) = error.field {
+                reply.insert("field".to_string(), json!(field));
+            }
+        }
+    }
+
+    Value::Object(reply)
+}
+
+/// Compares tokens and other secrets in constant time
+pub fn token_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn internal<T>(_: T) -> ControlError {
+    ControlError::new("internal")
+}
+
+/// Saves config after a change if `save_config` is enabled.
+/// If saving fails, `sites` from before the change are restored, so it is not applied either
+fn auto_save(config: &mut Config, sites: Vec<SiteConfig>) -> Result<(), ControlError> {
+    if config.save_config && config.save().is_none() {
+        config.sites = sites;
+        return Err(ControlError::new("save_failed"));
+    }
+    Ok(())
+}
+
+/// Handles control message, returns payload of the reply
+pub fn on_message(config: Arc<RwLock<Config>>, token: &ControlToken, addr: SocketAddr, data: &Message) -> Result<Value, ControlError> {
+    let kind = get_str(data, "type")?;
+
+    if kind == "list_sites" {
+        let conf = config.read().map_err(internal)?;
+        Ok(json!({
+            "sites": conf.sites.iter()
+                .filter(|o| token.allows(&o.domain))
+                .map(site_json)
+                .collect::<Vec<Value>>()
+        }))
+    } else if kind == "get_site" {
+        let domain = get_str(data, "domain")?;
+        if !token.allows(domain) { return Err(unauthorized(kind, addr)) }
+        let conf = config.read().map_err(internal)?;
+        Ok(json!({
+            "site": conf.sites.iter().find(|o| o.domain == domain).map(site_json)
+        }))
+    } else if kind == "delete_site" {
+        let domain = get_str(data, "domain")?;
+        if !token.allows(domain) { return Err(unauthorized(kind, addr)) }
+        let mut conf = config.write().map_err(internal)?;
+        let sites = conf.sites.clone();
+        conf.sites.retain(|o| o.domain != domain);
+        let deleted = conf.sites.len() != sites.len();
+        if deleted {
+            auto_save(&mut conf, sites)?;
+            notify(json!({ "type": "event", "event": "site_deleted", "domain": domain }));
+        }
+        Ok(json!({
+            "domain": domain,
+            "deleted": deleted
+        }))
+    } else if kind == "get_config" {
+        if token.domains.is_some() { return Err(unauthorized(kind, addr)) }
+        let conf = config.read().map_err(internal)?;
+        Ok(json!({
+            "config": config_json(&conf)
+        }))
+    } else if kind == "set_site" {
+        let domain = get_str(data, "domain")?;
+        if !token.allows(domain) { return Err(unauthorized(kind, addr)) }
+
+        let host = get_str(data, "host")?.to_string();
+        let enable_keep_alive = get_bool(data, "enable_keep_alive")?;
+        let support_keep_alive = get_bool(data, "support_keep_alive")?;
+        let ip_forwarding = IpForwarding::from_name(get_str(data, "ip_forwarding")?)
+            .ok_or(ControlError::field("invalid_field", "ip_forwarding"))?;
+        let replace_host = get_opt_str(data, "replace_host")?.map(|o| o.to_string());
+        let upstream_protocol = match get_opt_str(data, "upstream_protocol")? {
+            Some(o) => Some(UpstreamProtocol::from_name(o)
+                .ok_or(ControlError::field("invalid_field", "upstream_protocol"))?),
+            None => None
+        };
+        let ssl = get_cert(&config, token, domain, data)?;
+
+        let mut timeouts = Vec::new();
+        for field in ["connect_timeout", "upstream_timeout", "idle_timeout", "request_timeout"] {
+            timeouts.push(get_opt_number(data, field)?.map(Duration::from_secs));
+        }
+        let max_connections = get_opt_number(data, "max_connections")?.map(|o| o as usize);
+        let pool_max_idle = match data.get("pool_max_idle") {
+            None | Some(Value::Null) => None,
+            Some(o) => Some(o.as_u64().ok_or(ControlError::field("invalid_field", "pool_max_idle"))? as usize)
+        };
+        let pool_idle_timeout = get_opt_number(data, "pool_idle_timeout")?.map(Duration::from_secs);
+
+        let mut conf = config.write().map_err(internal)?;
+
+        let mut site = conf.sites.iter().find(|o| o.domain == domain).cloned().unwrap_or(SiteConfig {
+            domain: domain.to_string(),
+            host: host.clone(),
+            enable_keep_alive,
+            support_keep_alive,
+            ip_forwarding: ip_forwarding.clone(),
+            replace_host: None,
+            upstream_protocol: UpstreamProtocol::Http1,
+            upstream_tls_verify: true,
+            ssl: None,
+            connect_timeout: None,
+            upstream_timeout: None,
+            idle_timeout: None,
+            request_timeout: None,
+            max_connections: None,
+            pool_max_idle: None,
+            pool_idle_timeout: None
+        });
+
+        site.host = host;
+        site.enable_keep_alive = enable_keep_alive;
+        site.support_keep_alive = support_keep_alive;
+        site.ip_forwarding = ip_forwarding;
+        if data.contains_key("replace_host") {
+            site.replace_host = replace_host;
+        }
+        if let Some(protocol) = upstream_protocol {
+            site.upstream_protocol = protocol;
+        }
+        if let Some(ssl) = ssl {
+            site.ssl = ssl;
+        }
+        let fields = [
+            ("connect_timeout", &mut site.connect_timeout),
+            ("upstream_timeout", &mut site.upstream_timeout),
+            ("idle_timeout", &mut site.idle_timeout),
+            ("request_timeout", &mut site.request_timeout)
+        ];
+        for ((field, target), value) in fields.into_iter().zip(&timeouts) {
+            if data.contains_key(field) {
+                *target = *value;
+            }
+        }
+        if data.contains_key("max_connections") {
+            site.max_connections = max_connections;
+        }
+        if data.contains_key("pool_max_idle") {
+            site.pool_max_idle = pool_max_idle;
+        }
+        if data.contains_key("pool_idle_timeout") {
+            site.pool_idle_timeout = pool_idle_timeout;
+        }
+
+        // Site that config file would not accept is rejected before anything is changed
+        let site = site.check().map_err(|o| ControlError::field("invalid_field", o))?;
+
+        let sites = conf.sites.clone();
+        match conf.sites.iter_mut().find(|o| o.domain == domain) {
+            Some(o) => *o = site,
+            None => conf.sites.push(site)
+        }
+
+        auto_save(&mut conf, sites)?;
+        notify(json!({ "type": "event", "event": "site_changed", "domain": domain }));
+
+        Ok(Value::Null)
+    } else if kind == "set_certificate" {
+        let domain = get_str(data, "domain")?;
+        if !token.allows(domain) { return Err(unauthorized(kind, addr)) }
+        let ssl = get_cert(&config, token, domain, data)?.ok_or(ControlError::field("missing_field", "ssl_cert"))?;
+
+        let mut conf = config.write().map_err(internal)?;
+        let sites = conf.sites.clone();
+        let site = conf.sites.iter_mut()
+            .find(|o| o.domain == domain)
+            .ok_or(ControlError::field("not_found", "domain"))?;
+        site.ssl = ssl;
+
+        auto_save(&mut conf, sites)?;
+        notify(json!({ "type": "event", "event": "site_changed", "domain": domain }));
+
+        Ok(Value::Null)
+    } else if kind == "get_health" {
+        let sites: Vec<SiteConfig> = config.read().map_err(internal)?.sites.iter()
+            .filter(|o| token.allows(&o.domain))
+            .cloned()
+            .collect();
+        Ok(json!({
+            "sites": sites.iter().map(|site| json!({
+                "domain": site.domain,
+                "host": site.host,
+                "up": UpstreamStream::connect(&site.host, site.connect_timeout).map(|o| o.close()).is_some()
+            })).collect::<Vec<Value>>()
+        }))
+    } else if kind == "get_stats" {
+        Ok(json!({
+            "connections": STATS.connections.load(Ordering::Relaxed),
+            "active_connections": STATS.active_connections.load(Ordering::Relaxed),
+            "requests": STATS.requests.load(Ordering::Relaxed),
+            "workers": STATS.workers(),
+            "tasks": STATS.tasks(),
+            "rejected_connections": STATS.rejected_connections.load(Ordering::Relaxed),
+            "idle_upstream_connections": upstream::idle_connections(),
+            "reused_upstream_connections": STATS.reused_connections.load(Ordering::Relaxed),
+            "pool_utilization": STATS.utilization()
+        }))
+    } else if kind == "reload" {
+        if token.domains.is_some() { return Err(unauthorized(kind, addr)) }
+        reload_config(&config)?;
+        Ok(Value::Null)
+    } else if kind == "save_config" {
+        if token.domains.is_some() { return Err(unauthorized(kind, addr)) }
+        config.read().map_err(internal)?.save().ok_or(ControlError::new("save_failed"))?;
+        Ok(Value::Null)
+    } else {
+        Err(ControlError::field("unknown_type", "type"))
+    }
+}
+
+/// Finds configured control token
+pub fn authenticate(config: &Arc<RwLock<Config>>, addr: SocketAddr, token: &str) -> Result<ControlToken, ControlError> {
+    config.read().map_err(internal)?.websocket_tokens.iter()
+        .find(|o| token_eq(&o.token, token))
+        .cloned()
+        .ok_or_else(|| unauthorized("auth", addr))
+}
@@ -0,0 +1,183 @@
+use std::{future::Future, io, pin::Pin, time::Duration};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    time::Instant
+};
+use tokio_io_timeout::TimeoutStream;
+
+/// Size of the copy buffer and of the pipe chunks spliced at once
+pub const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Plain TCP socket under a stream with timeouts of the stream
+pub struct Tcp<'a> {
+    pub stream: &'a TcpStream,
+    pub read_timeout: Option<Duration>,
+    pub write_timeout: Option<Duration>
+}
+
+/// Stream that can expose its plain TCP socket, so bytes can skip user space.
+/// Streams that transform data (TLS) keep the default
+pub trait AsTcp {
+    fn as_tcp(&self) -> Option<Tcp<'_>> {
+        None
+    }
+}
+
+impl AsTcp for TcpStream {
+    fn as_tcp(&self) -> Option<Tcp<'_>> {
+        Some(Tcp { stream: self, read_timeout: None, write_timeout: None })
+    }
+}
+
+impl<S: AsTcp> AsTcp for &mut S {
+    fn as_tcp(&self) -> Option<Tcp<'_>> {
+        (**self).as_tcp()
+    }
+}
+
+impl<S: AsTcp + AsyncRead> AsTcp for BufReader<S> {
+    fn as_tcp(&self) -> Option<Tcp<'_>> {
+        self.get_ref().as_tcp()
+    }
+}
+
+impl<S: AsTcp + AsyncRead + AsyncWrite> AsTcp for Pin<Box<TimeoutStream<S>>> {
+    fn as_tcp(&self) -> Option<Tcp<'_>> {
+        let stream: &TimeoutStream<S> = self;
+        Some(Tcp {
+            read_timeout: stream.read_timeout(),
+            write_timeout: stream.write_timeout(),
+            ..stream.get_ref().as_tcp()?
+        })
+    }
+}
+
+/// Forwards `length` bytes, or all until EOF if `None`, from `from` to `to` and returns count of them.
+/// Bytes buffered in `from` go first. The rest is spliced if `zero_copy` and both sides are plain TCP
+/// on Linux, otherwise copied through `buf`. Fails with `TimedOut` when `deadline` passes
+pub async fn forward<R, W>(
+    from: &mut BufReader<R>,
+    to: &mut W,
+    length: Option<usize>,
+    buf: &mut Vec<u8>,
+    zero_copy: bool,
+    deadline: Option<Instant>
+) -> io::Result<usize>
+where
+    R: AsyncRead + AsTcp + Unpin,
+    W: AsyncWrite + AsTcp + Unpin
+{
+    let buffered = from.buffer().len().min(length.unwrap_or(usize::MAX));
+    if buffered > 0 {
+        to.write_all(&from.buffer()[..buffered]).await?;
+        from.consume(buffered);
+    }
+
+    let length = length.map(|o| o - buffered);
+    if length == Some(0) {
+        return Ok(buffered);
+    }
+
+    #[cfg(target_os = "linux")]
+    if zero_copy {
+        if let (Some(reader), Some(writer)) = (from.get_ref().as_tcp(), to.as_tcp()) {
+            return Ok(buffered + splice::splice(reader, writer, length, deadline).await?);
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = zero_copy;
+
+    if buf.len() < BUFFER_SIZE {
+        buf.resize(BUFFER_SIZE, 0);
+    }
+
+    let mut total = 0;
+    while length.is_none_or(|o| total < o) {
+        let size = length.map_or(buf.len(), |o| (o - total).min(buf.len()));
+        let size = until(deadline, from.get_mut().read(&mut buf[..size])).await?;
+        if size == 0 { break }
+        until(deadline, to.write_all(&buf[..size])).await?;
+        total += size;
+    }
+
+    Ok(buffered + total)
+}
+
+/// Waits for `future` until `deadline`
+async fn until<T>(deadline: Option<Instant>, future: impl Future<Output = io::Result<T>>) -> io::Result<T> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, future).await
+            .unwrap_or(Err(io::ErrorKind::TimedOut.into())),
+        None => future.await
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod splice {
+    use std::{io, os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd}, ptr, time::Duration};
+
+    use tokio::{io::Interest, time::Instant};
+
+    use super::{until, Tcp, BUFFER_SIZE};
+
+    /// Pipe between sockets, bytes are moved through it inside the kernel
+    struct Pipe {
+        read: OwnedFd,
+        write: OwnedFd
+    }
+
+    impl Pipe {
+        fn new() -> io::Result<Pipe> {
+            let mut fds = [0; 2];
+            if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(unsafe { Pipe { read: OwnedFd::from_raw_fd(fds[0]), write: OwnedFd::from_raw_fd(fds[1]) } })
+        }
+    }
+
+    fn splice_fd(from: RawFd, to: RawFd, size: usize) -> io::Result<usize> {
+        let size = unsafe {
+            libc::splice(from, ptr::null_mut(), to, ptr::null_mut(), size, libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK)
+        };
+        if size < 0 { Err(io::Error::last_os_error()) } else { Ok(size as usize) }
+    }
+
+    /// Limit of a single wait, the closest of socket timeout and `deadline`
+    fn limit(timeout: Option<Duration>, deadline: Option<Instant>) -> Option<Instant> {
+        match (timeout.map(|o| Instant::now() + o), deadline) {
+            (Some(timeout), Some(deadline)) => Some(timeout.min(deadline)),
+            (timeout, deadline) => timeout.or(deadline)
+        }
+    }
+
+    /// Moves `length` bytes, or all until EOF if `None`, between sockets with `splice(2)`
+    pub async fn splice(from: Tcp<'_>, to: Tcp<'_>, length: Option<usize>, deadline: Option<Instant>) -> io::Result<usize> {
+        let pipe = Pipe::new()?;
+        let mut total = 0;
+
+        while length.is_none_or(|o| total < o) {
+            let chunk = length.map_or(BUFFER_SIZE, |o| (o - total).min(BUFFER_SIZE));
+
+            let size = until(
+                limit(from.read_timeout, deadline),
+                from.stream.async_io(Interest::READABLE, || splice_fd(from.stream.as_raw_fd(), pipe.write.as_raw_fd(), chunk))
+            ).await?;
+            if size == 0 { break }
+
+            let mut left = size;
+            while left > 0 {
+                left -= until(
+                    limit(to.write_timeout, deadline),
+                    to.stream.async_io(Interest::WRITABLE, || splice_fd(pipe.read.as_raw_fd(), to.stream.as_raw_fd(), left))
+                ).await?;
+            }
+
+            total += size;
+        }
+
+        Ok(total)
+    }
+}
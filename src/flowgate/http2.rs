@@ -0,0 +1,801 @@
+use std::{
+    collections::BTreeMap,
+    future::poll_fn,
+    io,
+    net::SocketAddr,
+    sync::{atomic::{AtomicBool, AtomicUsize, Ordering}, Arc, Mutex, RwLock},
+    time::Duration
+};
+
+use bytes::Bytes;
+use h2::{client::{ResponseFuture, SendRequest}, server::SendResponse, Reason, RecvStream, SendStream};
+use http::{header::CONTENT_LENGTH, HeaderMap, HeaderName, HeaderValue, Method, Request, Response, StatusCode};
+use log::info;
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    sync::watch,
+    task::JoinSet,
+    time::sleep
+};
+
+use super::{
+    config::{Config, IpForwarding, ListenerConfig, SiteConfig, UpstreamProtocol, DEFAULT_POOL_IDLE_TIMEOUT},
+    server::FlowgateServer,
+    stats::STATS
+};
+
+/// Largest body part read at once, as much as fits into one DATA frame
+pub const MAX_FRAME_SIZE: usize = 16384;
+
+/// Flow-control window granted to HTTP/2 site servers for each stream
+const UPSTREAM_WINDOW_SIZE: u32 = 1048576;
+
+/// Flow-control window granted to HTTP/2 site servers for a session shared by many streams
+const UPSTREAM_CONNECTION_WINDOW_SIZE: u32 = 16777216;
+
+/// Headers that are specific to HTTP/1.1 connections and must not be forwarded
+const CONNECTION_HEADERS: [&str; 5] = ["connection", "keep-alive", "proxy-connection", "transfer-encoding", "upgrade"];
+
+/// Session to the HTTP/2 site server
+#[derive(Default)]
+struct Session {
+    /// Empty until connected and after it's closed
+    sender: tokio::sync::Mutex<Option<SendRequest<Bytes>>>,
+    /// Requests in progress
+    requests: AtomicUsize,
+    /// Whether a request was opened since the last idle check
+    used: AtomicBool
+}
+
+/// Request in progress over a session, which isn't idle until it's dropped
+struct Active(Arc<Session>);
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        self.0.requests.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Sessions to HTTP/2 site servers by domain and host
+static SESSIONS: Mutex<BTreeMap<(String, String), Arc<Session>>> = Mutex::new(BTreeMap::new());
+
+/// Serves HTTP/2 connection negotiated via ALPN, each stream on its own task.
+/// Connection without streams for `idle_timeout` of the last site, or with the server
+/// shutting down, is closed with GOAWAY
+pub async fn serve(
+    config: Arc<RwLock<Config>>,
+    listener: &ListenerConfig,
+    stream: impl AsyncRead + AsyncWrite + Unpin,
+    addr: SocketAddr,
+    mut shutdown: watch::Receiver<bool>
+) -> Option<()> {
+    let settings = config.read().ok()?.http2.clone()?;
+    let timeout = config.read().ok()?.connection_timeout;
+
+    let handshake = h2::server::Builder::new()
+        .max_concurrent_streams(settings.max_concurrent_streams)
+        .initial_window_size(settings.initial_window_size)
+        .initial_connection_window_size(settings.connection_window_size)
+        .handshake(stream);
+    let mut conn = tokio::time::timeout(timeout, handshake).await.ok()?.ok()?;
+
+    let mut streams = JoinSet::new();
+    let mut closing = *shutdown.borrow();
+    let mut idle = timeout;
+
+    if closing {
+        conn.graceful_shutdown();
+    }
+
+    loop {
+        tokio::select! {
+            request = conn.accept() => {
+                let Some(Ok((request, respond))) = request else { break };
+                streams.spawn(handle(config.clone(), listener.clone(), request, respond, addr));
+            },
+            Some(result) = streams.join_next() => {
+                if let Ok(Some(timeout)) = result {
+                    idle = timeout;
+                }
+            },
+            // Server is shutting down, active streams are finished before closing
+            _ = shutdown.changed(), if !closing => {
+                conn.graceful_shutdown();
+                closing = true;
+            },
+            // Client that doesn't answer GOAWAY in time is dropped
+            _ = sleep(if closing { timeout } else { idle }), if streams.is_empty() => {
+                if closing { break }
+                conn.graceful_shutdown();
+                closing = true;
+            }
+        }
+    }
+
+    Some(())
+}
+
+/// Forwards request of the client stream to the site server,
+/// returns `idle_timeout` of the site if it has one
+async fn handle(
+    config: Arc<RwLock<Config>>,
+    listener: ListenerConfig,
+    request: Request<RecvStream>,
+    mut respond: SendResponse<Bytes>,
+    addr: SocketAddr
+) -> Option<Duration> {
+    let (head, body) = request.into_parts();
+    let path = head.uri.path_and_query().map_or("/", |o| o.as_str());
+
+    let host = head.uri.authority().map(|o| o.to_string())
+        .or_else(|| head.headers.get("host")?.to_str().ok().map(str::to_string));
+    let Some(host) = host else {
+        reply(&mut respond, StatusCode::BAD_REQUEST);
+        return None;
+    };
+
+    // :authority may contain default port, which is usually omitted in Host header
+    let Some(site) = config.read().ok().and_then(|o| {
+        o.get_listener_site(&listener, &host)
+            .or_else(|| o.get_listener_site(&listener, host.rsplit_once(':')?.0))
+            .cloned()
+    }) else {
+        reply(&mut respond, StatusCode::MISDIRECTED_REQUEST);
+        return None;
+    };
+
+    if head.method == Method::CONNECT {
+        reply(&mut respond, StatusCode::METHOD_NOT_ALLOWED);
+        return site.idle_timeout;
+    }
+
+    STATS.request();
+    info!("{addr} > {} https://{host}{path}", head.method);
+
+    let fields: Vec<(String, String)> = head.headers.iter()
+        .map(|(k, v)| (k.to_string(), String::from_utf8_lossy(v.as_bytes()).to_string()))
+        .collect();
+
+    let forwarded = async {
+        if site.upstream_protocol == UpstreamProtocol::Http1 {
+            let request = http1_request(&site, head.method.as_str(), path, &host, &fields, body.is_end_stream(), addr);
+            forward(&site, request, &head.method, body, &mut respond).await
+        } else {
+            let request = h2_request(&site, head.method.as_str(), path, &host, &fields, addr);
+            forward_h2(&site, request, body, &mut respond).await
+        }
+    };
+
+    // Stream that exceeds `request_timeout` gets 504, or is reset if the response has started
+    let forwarded = match site.request_timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, forwarded).await {
+            Ok(forwarded) => forwarded,
+            Err(_) => reply(&mut respond, StatusCode::GATEWAY_TIMEOUT)
+        },
+        None => forwarded.await
+    };
+
+    if forwarded.is_none() {
+        respond.send_reset(Reason::INTERNAL_ERROR);
+    }
+
+    site.idle_timeout
+}
+
+/// Sends response without body
+fn reply(respond: &mut SendResponse<Bytes>, status: StatusCode) -> Option<()> {
+    let mut response = Response::new(());
+    *response.status_mut() = status;
+    respond.send_response(response, true).ok().map(|_| ())
+}
+
+/// Builds HTTP/1.1 request to the site server,
+/// returns it with flag whether the body should be sent chunked
+fn http1_request(
+    site: &SiteConfig,
+    method: &str,
+    path: &str,
+    host: &str,
+    fields: &[(String, String)],
+    end_stream: bool,
+    addr: SocketAddr
+) -> Option<(Vec<u8>, bool)> {
+    let mut head = format!("{method} {path} HTTP/1.1\r\nHost: {host}");
+    let mut cookies = Vec::new();
+    let mut content_length = None;
+
+    for (key, value) in fields {
+        match key.as_str() {
+            "content-length" => content_length = Some(value),
+            "cookie" => cookies.push(value.as_str()),
+            "te" if value != "trailers" => {},
+            "host" | "expect" => {},
+            key if CONNECTION_HEADERS.contains(&key) => {},
+            _ => head.push_str(&format!("\r\n{key}: {value}"))
+        }
+    }
+
+    if !cookies.is_empty() {
+        head.push_str(&format!("\r\ncookie: {}", cookies.join("; ")));
+    }
+
+    if !end_stream {
+        match content_length {
+            Some(length) => head.push_str(&format!("\r\nContent-Length: {length}")),
+            None => head.push_str("\r\nTransfer-Encoding: chunked")
+        }
+    }
+
+    head.push_str(if site.support_keep_alive { "\r\nConnection: keep-alive" } else { "\r\nConnection: close" });
+
+    Some((FlowgateServer::build_request(site, &head, addr)?, !end_stream && content_length.is_none()))
+}
+
+/// Builds HTTP/2 request to the site server
+fn h2_request(
+    site: &SiteConfig,
+    method: &str,
+    path: &str,
+    host: &str,
+    fields: &[(String, String)],
+    addr: SocketAddr
+) -> Option<Request<()>> {
+    let scheme = if site.upstream_protocol == UpstreamProtocol::H2 { "https" } else { "http" };
+    let authority = site.replace_host.as_deref().unwrap_or(host);
+    let forwarded = match &site.ip_forwarding {
+        IpForwarding::Header(header) => Some(header.to_lowercase()),
+        _ => None
+    };
+
+    let mut request = Request::builder()
+        .method(method)
+        .uri(format!("{scheme}://{authority}{path}"));
+
+    for (key, value) in fields {
+        match key.as_str() {
+            "host" | "expect" => {},
+            "te" if value != "trailers" => {},
+            key if CONNECTION_HEADERS.contains(&key) => {},
+            key if Some(key) == forwarded.as_deref() => {},
+            _ => request = request.header(key, value)
+        }
+    }
+
+    if let Some(header) = forwarded {
+        request = request.header(header, addr.to_string());
+    }
+
+    request.body(()).ok()
+}
+
+/// Exchanges request with the HTTP/1.1 site server over pooled connection if there is one.
+/// Response body is read only as fast as the client window allows
+async fn forward(
+    site: &SiteConfig,
+    request: Option<(Vec<u8>, bool)>,
+    method: &Method,
+    mut body: RecvStream,
+    respond: &mut SendResponse<Bytes>
+) -> Option<()> {
+    let Some((request, chunked)) = request else {
+        return reply(respond, StatusCode::BAD_REQUEST);
+    };
+
+    // Pooled connection can be closed by the server at any moment,
+    // so idempotent requests without body are sent again over a new connection then
+    let mut reuse = true;
+    let mut socket;
+    let (mut upstream, status, headers, reused) = loop {
+        // Stale connection gives its slot back before a new one is opened
+        socket = None;
+        let Some((new, reused)) = FlowgateServer::connect(site, reuse).await else {
+            return reply(respond, if site.is_full() { StatusCode::SERVICE_UNAVAILABLE } else { StatusCode::BAD_GATEWAY });
+        };
+        let mut upstream = FlowgateServer::upstream(site, socket.insert(new));
+        let retry = reused && method.is_idempotent() && body.is_end_stream();
+        reuse = false;
+
+        let head = match send_request(&mut upstream, &request, &mut body, chunked).await {
+            Some(()) => read_response_head(&mut upstream).await,
+            None => Err(io::ErrorKind::BrokenPipe.into())
+        };
+        let timed_out = head.as_ref().is_err_and(|e| matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut));
+
+        match head {
+            Ok((status, headers)) => break (upstream, status, headers, reused),
+            Err(_) if retry && !timed_out => continue,
+            Err(_) => return reply(respond, if timed_out { StatusCode::GATEWAY_TIMEOUT } else { StatusCode::BAD_GATEWAY })
+        }
+    };
+
+    if reused {
+        STATS.reuse();
+    }
+
+    let is_chunked = headers.iter()
+        .any(|(k, v)| k == "transfer-encoding" && v.split(',').any(|o| o.trim() == "chunked"));
+    let content_length: Option<usize> = headers.iter()
+        .find(|(k, _)| k == "content-length")
+        .and_then(|o| o.1.parse().ok());
+
+    let mut response = Response::builder().status(status);
+    for (key, value) in headers.iter().filter(|(k, _)| !CONNECTION_HEADERS.contains(&k.as_str())) {
+        response = response.header(key, value);
+    }
+    let Ok(response) = response.body(()) else {
+        return reply(respond, StatusCode::BAD_GATEWAY);
+    };
+
+    let no_body = method == Method::HEAD || status == 204 || status == 304 || content_length == Some(0);
+
+    if no_body {
+        respond.send_response(response, true).ok()?;
+    } else {
+        let mut send = respond.send_response(response, false).ok()?;
+
+        if is_chunked {
+            read_chunked(&mut upstream, &mut send).await?;
+        } else {
+            stream_data(&mut upstream, content_length, &mut send).await?;
+            send.send_data(Bytes::new(), true).ok()?;
+        }
+    }
+
+    // Connection goes back to the pool only when the response was read up to its end
+    let complete = (no_body || is_chunked || content_length.is_some())
+        && status != 101
+        && upstream.buffer().is_empty()
+        && !headers.iter().any(|(k, v)| k == "connection" && v.eq_ignore_ascii_case("close"));
+
+    if complete {
+        drop(upstream);
+        site.release(socket?);
+    }
+
+    Some(())
+}
+
+/// Sends request with the client body to the HTTP/1.1 site server
+async fn send_request(
+    upstream: &mut (impl AsyncWrite + Unpin),
+    request: &[u8],
+    body: &mut RecvStream,
+    chunked: bool
+) -> Option<()> {
+    upstream.write_all(request).await.ok()?;
+    write_http1_body(upstream, body, chunked).await
+}
+
+/// Reads HTTP/1.1 response head, skipping informational responses
+async fn read_response_head(reader: &mut (impl AsyncBufRead + Unpin)) -> io::Result<(u16, Vec<(String, String)>)> {
+    let invalid = || io::Error::from(io::ErrorKind::InvalidData);
+
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).await?;
+        let status: u16 = line.split(' ').nth(1).and_then(|o| o.trim().parse().ok()).ok_or_else(invalid)?;
+
+        let mut headers = Vec::new();
+
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).await? == 0 { return Err(io::ErrorKind::UnexpectedEof.into()) }
+            let line = line.trim_end_matches(['\r', '\n']);
+            if line.is_empty() { break }
+            let (key, value) = line.split_once(':').ok_or_else(invalid)?;
+            headers.push((key.trim().to_lowercase(), value.trim().to_string()));
+        }
+
+        if (100..200).contains(&status) && status != 101 { continue }
+
+        return Ok((status, headers));
+    }
+}
+
+/// Exchanges request with the HTTP/2 site server. Bodies are relayed both ways at once,
+/// e.g. for gRPC streaming, each only as fast as the receiving side takes it
+async fn forward_h2(
+    site: &SiteConfig,
+    request: Option<Request<()>>,
+    mut body: RecvStream,
+    respond: &mut SendResponse<Bytes>
+) -> Option<()> {
+    let Some(request) = request else {
+        return reply(respond, StatusCode::BAD_REQUEST);
+    };
+
+    let end_stream = body.is_end_stream();
+    let Some((response, mut upload, _active)) = open_request(site, request, end_stream).await else {
+        return reply(respond, StatusCode::BAD_GATEWAY);
+    };
+
+    let upload = async {
+        if !end_stream {
+            relay(&mut body, &mut upload).await;
+        }
+    };
+    let download = async {
+        let Ok(response) = response.await else {
+            return reply(respond, StatusCode::BAD_GATEWAY);
+        };
+
+        let (head, mut body) = response.into_parts();
+        let end_stream = body.is_end_stream();
+        let mut send = respond.send_response(Response::from_parts(head, ()), end_stream).ok()?;
+
+        if end_stream { return Some(()) }
+
+        relay(&mut body, &mut send).await
+    };
+
+    tokio::pin!(upload, download);
+    let mut uploading = true;
+
+    loop {
+        tokio::select! {
+            _ = &mut upload, if uploading => uploading = false,
+            forwarded = &mut download => return forwarded
+        }
+    }
+}
+
+/// Relays body between HTTP/2 streams, giving window back to the sender
+/// only after the data is passed on. Reset of the sender is passed on too
+async fn relay(body: &mut RecvStream, send: &mut SendStream<Bytes>) -> Option<()> {
+    while let Some(data) = body.data().await {
+        let data = match data {
+            Ok(data) => data,
+            Err(e) => {
+                send.send_reset(e.reason().unwrap_or(Reason::CANCEL));
+                return None;
+            }
+        };
+
+        let size = data.len();
+        send_data(send, data).await?;
+        let _ = body.flow_control().release_capacity(size);
+    }
+
+    match body.trailers().await {
+        Ok(Some(trailers)) => send.send_trailers(trailers).ok(),
+        Ok(None) => send.send_data(Bytes::new(), true).ok(),
+        Err(e) => {
+            send.send_reset(e.reason().unwrap_or(Reason::CANCEL));
+            None
+        }
+    }
+}
+
+/// Sends data as the peer window allows
+async fn send_data(send: &mut SendStream<Bytes>, mut data: Bytes) -> Option<()> {
+    while !data.is_empty() {
+        let size = capacity(send, data.len()).await?;
+        send.send_data(data.split_to(size.min(data.len())), false).ok()?;
+    }
+
+    Some(())
+}
+
+/// Returns session to the server of `site`, shared by all requests to it
+fn session(site: &SiteConfig) -> Option<Arc<Session>> {
+    let mut sessions = SESSIONS.lock().ok()?;
+    Some(sessions.entry((site.domain.clone(), site.host.clone())).or_default().clone())
+}
+
+/// Opens stream to the server of `site` over its session, connecting it first if needed.
+/// The session is kept open while the returned `Active` lives
+async fn open_request(
+    site: &SiteConfig,
+    request: Request<()>,
+    end_stream: bool
+) -> Option<(ResponseFuture, SendStream<Bytes>, Active)> {
+    let shared = session(site)?;
+
+    let (sender, active) = {
+        let mut sender = shared.sender.lock().await;
+        if sender.is_none() {
+            *sender = Some(connect(site, &shared).await?);
+        }
+        shared.requests.fetch_add(1, Ordering::Relaxed);
+        shared.used.store(true, Ordering::Relaxed);
+        (sender.clone()?, Active(shared.clone()))
+    };
+
+    let mut sender = sender.ready().await.ok()?;
+    let (response, body) = sender.send_request(request, end_stream).ok()?;
+    Some((response, body, active))
+}
+
+/// Connects to the HTTP/2 site server
+async fn connect(site: &SiteConfig, session: &Arc<Session>) -> Option<SendRequest<Bytes>> {
+    let stream = site.connect_async().await?;
+    let idle_timeout = site.pool_idle_timeout.unwrap_or(DEFAULT_POOL_IDLE_TIMEOUT);
+
+    match site.upstream_protocol {
+        #[cfg(feature = "use-openssl")]
+        UpstreamProtocol::H2 => {
+            let domain = site.replace_host.clone().unwrap_or_else(|| {
+                site.host.rsplit_once(':').map(|o| o.0).unwrap_or(&site.host).to_string()
+            });
+            handshake(stream.connect_tls(&domain, site.upstream_tls_verify).await?, session, idle_timeout).await
+        },
+        #[cfg(not(feature = "use-openssl"))]
+        UpstreamProtocol::H2 => None,
+        _ => handshake(stream, session, idle_timeout).await
+    }
+}
+
+/// Starts HTTP/2 session over connection to the site server. The connection is driven
+/// by its own task, which forgets the session once the server closes it or it's idle
+/// for `idle_timeout`, then the connection is closed after requests in progress
+async fn handshake(
+    stream: impl AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    session: &Arc<Session>,
+    idle_timeout: Duration
+) -> Option<SendRequest<Bytes>> {
+    let (sender, connection) = h2::client::Builder::new()
+        .enable_push(false)
+        .initial_window_size(UPSTREAM_WINDOW_SIZE)
+        .initial_connection_window_size(UPSTREAM_CONNECTION_WINDOW_SIZE)
+        .handshake(stream)
+        .await
+        .ok()?;
+
+    let session = session.clone();
+    tokio::spawn(async move {
+        tokio::pin!(connection);
+
+        loop {
+            tokio::select! {
+                _ = &mut connection => break,
+                _ = sleep(idle_timeout) => {
+                    let mut sender = session.sender.lock().await;
+                    if session.requests.load(Ordering::Relaxed) == 0 && !session.used.swap(false, Ordering::Relaxed) {
+                        // Connection ends once its last sender is dropped
+                        *sender = None;
+                        forget(&session);
+                    }
+                }
+            }
+        }
+
+        *session.sender.lock().await = None;
+        forget(&session);
+    });
+
+    Some(sender)
+}
+
+/// Removes session from the shared ones, next request opens a new one
+fn forget(session: &Arc<Session>) {
+    if let Ok(mut sessions) = SESSIONS.lock() {
+        sessions.retain(|_, o| !Arc::ptr_eq(o, session));
+    }
+}
+
+/// Waits until the peer window allows to send data on the stream, returns how much
+async fn capacity(body: &mut SendStream<Bytes>, size: usize) -> Option<usize> {
+    body.reserve_capacity(size);
+
+    while body.capacity() == 0 {
+        poll_fn(|cx| body.poll_capacity(cx)).await?.ok()?;
+    }
+
+    Some(body.capacity())
+}
+
+/// Reads `length` bytes of body (or until EOF) and sends them as data,
+/// reading at most a frame at a time and only as far as the peer window allows
+async fn stream_data(
+    reader: &mut (impl AsyncRead + Unpin),
+    length: Option<usize>,
+    body: &mut SendStream<Bytes>
+) -> Option<()> {
+    let mut left = length.unwrap_or(usize::MAX);
+
+    while left > 0 {
+        let size = capacity(body, left.min(MAX_FRAME_SIZE)).await?;
+        let mut data = vec![0; size.min(left).min(MAX_FRAME_SIZE)];
+        let size = reader.read(&mut data).await.ok()?;
+
+        if size == 0 {
+            return length.is_none().then_some(());
+        }
+
+        data.truncate(size);
+        left -= size;
+
+        body.send_data(data.into(), false).ok()?;
+    }
+
+    Some(())
+}
+
+/// Reads chunked body and sends it with trailers, if there are any
+async fn read_chunked(reader: &mut (impl AsyncBufRead + Unpin), body: &mut SendStream<Bytes>) -> Option<()> {
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).await.ok()?;
+        let length = usize::from_str_radix(line.split(';').next()?.trim(), 16).ok()?;
+
+        if length == 0 {
+            let mut trailers = HeaderMap::new();
+
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).await.ok()? == 0 { break }
+                let line = line.trim_end_matches(['\r', '\n']);
+                if line.is_empty() { break }
+                if let Some((key, value)) = line.split_once(':') {
+                    if let (Ok(key), Ok(value)) = (HeaderName::from_bytes(key.trim().as_bytes()), HeaderValue::from_str(value.trim())) {
+                        trailers.append(key, value);
+                    }
+                }
+            }
+
+            return if trailers.is_empty() {
+                body.send_data(Bytes::new(), true).ok()
+            } else {
+                body.send_trailers(trailers).ok()
+            };
+        }
+
+        stream_data(reader, Some(length), body).await?;
+
+        let mut line = String::new();
+        reader.read_line(&mut line).await.ok()?;
+    }
+}
+
+/// Forwards HTTP/1.1 request to the HTTP/2 site server, streaming request and response bodies
+/// at the same time. Returns whether the client connection can be kept alive
+pub async fn forward_http1<S: AsyncRead + AsyncWrite + Unpin>(
+    site: &SiteConfig,
+    stream: &mut BufReader<S>,
+    head_str: &str,
+    keep_alive: bool,
+    addr: SocketAddr
+) -> Option<bool> {
+    let mut lines = head_str.split("\r\n");
+    let mut status = lines.next()?.split(' ');
+    let method = status.next()?.to_string();
+    let path = status.next()?.to_string();
+
+    let fields: Vec<(String, String)> = lines
+        .filter_map(|o| o.split_once(':'))
+        .map(|(k, v)| (k.trim().to_lowercase(), v.trim().to_string()))
+        .collect();
+
+    let host = fields.iter().find(|o| o.0 == "host")?.1.clone();
+    let content_length: Option<usize> = fields.iter()
+        .find(|o| o.0 == "content-length")
+        .and_then(|o| o.1.parse().ok());
+    let is_chunked = fields.iter()
+        .any(|(k, v)| k == "transfer-encoding" && v.split(',').any(|o| o.trim() == "chunked"));
+    let has_body = is_chunked || content_length.is_some_and(|o| o > 0);
+
+    let Some(request) = h2_request(site, &method, &path, &host, &fields, addr) else {
+        FlowgateServer::respond_error(stream, "400 Bad Request").await;
+        return None;
+    };
+
+    let Some((response, mut body, _active)) = open_request(site, request, !has_body).await else {
+        FlowgateServer::respond_error(stream, "502 Bad Gateway").await;
+        return None;
+    };
+
+    let (reader, mut writer) = tokio::io::split(&mut *stream);
+    let mut reader = BufReader::new(reader);
+
+    let (responded, uploaded) = {
+        let upload = async {
+            if !has_body { return true }
+            let sent = read_http1_body(&mut reader, content_length, is_chunked, &mut body).await.is_some();
+            if !sent {
+                body.send_reset(Reason::CANCEL);
+            }
+            sent
+        };
+        let download = write_http1_response(&mut writer, response, &method, keep_alive);
+
+        tokio::pin!(upload, download);
+        let mut uploaded = None;
+
+        loop {
+            tokio::select! {
+                sent = &mut upload, if uploaded.is_none() => uploaded = Some(sent),
+                responded = &mut download => break (responded, uploaded == Some(true))
+            }
+        }
+    };
+
+    responded?;
+
+    // Body left unread or read ahead by the buffer can't be passed to the next request
+    Some(keep_alive && uploaded && reader.buffer().is_empty())
+}
+
+/// Sends HTTP/1.1 request body to the HTTP/2 site server
+async fn read_http1_body(
+    reader: &mut (impl AsyncBufRead + Unpin),
+    content_length: Option<usize>,
+    is_chunked: bool,
+    body: &mut SendStream<Bytes>
+) -> Option<()> {
+    if is_chunked {
+        return read_chunked(reader, body).await;
+    }
+
+    stream_data(reader, content_length, body).await?;
+    body.send_data(Bytes::new(), true).ok()
+}
+
+/// Writes response of the HTTP/2 site server to HTTP/1.1 client, chunked unless its length is known.
+/// Window is given back to the server only after the data is written
+async fn write_http1_response(
+    writer: &mut (impl AsyncWrite + Unpin),
+    response: ResponseFuture,
+    method: &str,
+    keep_alive: bool
+) -> Option<()> {
+    let Ok(response) = response.await else {
+        FlowgateServer::respond_error(writer, "502 Bad Gateway").await;
+        return None;
+    };
+
+    let (head, mut body) = response.into_parts();
+    let has_body = method != "HEAD" && !matches!(head.status.as_u16(), 100..=199 | 204 | 304);
+    let chunked = has_body && !head.headers.contains_key(CONTENT_LENGTH);
+
+    let mut response = format!("HTTP/1.1 {} {}\r\n", head.status.as_u16(), head.status.canonical_reason().unwrap_or(""));
+
+    for (key, value) in &head.headers {
+        if CONNECTION_HEADERS.contains(&key.as_str()) { continue }
+        response.push_str(&format!("{key}: {}\r\n", String::from_utf8_lossy(value.as_bytes())));
+    }
+
+    if chunked {
+        response.push_str("Transfer-Encoding: chunked\r\n");
+    }
+
+    response.push_str(if keep_alive { "Connection: keep-alive\r\n\r\n" } else { "Connection: close\r\n\r\n" });
+    writer.write_all(response.as_bytes()).await.ok()?;
+
+    if !has_body {
+        return writer.flush().await.ok();
+    }
+
+    write_http1_body(writer, &mut body, chunked).await
+}
+
+/// Writes HTTP/2 body to HTTP/1.1 peer, chunked with trailers if `chunked`.
+/// Window is given back to the sender only after the data is written
+async fn write_http1_body(writer: &mut (impl AsyncWrite + Unpin), body: &mut RecvStream, chunked: bool) -> Option<()> {
+    while let Some(data) = body.data().await {
+        let data = data.ok()?;
+
+        // Empty chunk would end the chunked body
+        if !data.is_empty() {
+            if chunked {
+                writer.write_all(format!("{:x}\r\n", data.len()).as_bytes()).await.ok()?;
+                writer.write_all(&data).await.ok()?;
+                writer.write_all(b"\r\n").await.ok()?;
+            } else {
+                writer.write_all(&data).await.ok()?;
+            }
+        }
+
+        let _ = body.flow_control().release_capacity(data.len());
+    }
+
+    if chunked {
+        let mut trailers = String::from("0\r\n");
+        for (key, value) in body.trailers().await.ok()?.iter().flatten() {
+            trailers.push_str(&format!("{key}: {}\r\n", String::from_utf8_lossy(value.as_bytes())));
+        }
+        trailers.push_str("\r\n");
+        writer.write_all(trailers.as_bytes()).await.ok()?;
+    }
+
+    writer.flush().await.ok()
+}
@@ -1,73 +1,171 @@
 use std::{
-    io::{Read, Write}, net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, TcpListener, TcpStream}, str::FromStr, sync::{Arc, RwLock}, thread, time::Duration
+    io, net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6}, pin::Pin, str::FromStr, sync::{atomic::Ordering, Arc, RwLock}, time::Duration
 };
 
-use log::info;
-use threadpool::ThreadPool;
+use base64::prelude::*;
+use log::{error, info};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    runtime::{Builder, Runtime},
+    sync::watch,
+    time::Instant
+};
+use tokio_io_timeout::TimeoutStream;
+
+use super::{forward::{self, AsTcp}, config::{Config,SiteConfig,IpForwarding,ForwardProxyConfig,ListenerConfig,UpstreamProtocol}, control::token_eq, http2, stats::{ConnectionGuard, STATS}, upgrade, upstream::{self, AsyncUpstreamStream}};
 
-use super::{closeable::Closeable, config::{Config,SiteConfig,IpForwarding}};
+/// Stream with read and write timeouts, like a blocking socket has
+type Timed<S> = Pin<Box<TimeoutStream<S>>>;
 
 pub struct FlowgateServer {
     config: Arc<RwLock<Config>>,
+    runtime: Runtime,
+    /// Set to `true` to stop accepting connections and close idle ones
+    shutdown: watch::Sender<bool>
 }
 
+/// Connection to the site server with timeouts, borrowed to return it to the pool afterwards
+type Upstream<'a> = BufReader<Timed<&'a mut AsyncUpstreamStream>>;
+
+/// Client keep-alive connection to a site, upstream connection is taken for each request
 struct Connection {
-    stream: TcpStream, 
     config: SiteConfig,
-    keep_alive: bool, 
+    keep_alive: bool,
     host: String,
+    /// Copy buffer for bodies that can't be spliced
+    buffer: Vec<u8>
+}
+
+/// Client connection, plain or TLS
+pub trait ClientStream: AsyncRead + AsyncWrite + AsTcp + Unpin + Send {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>);
+}
+
+impl ClientStream for Timed<TcpStream> {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.as_mut().set_read_timeout_pinned(timeout);
+    }
+}
+
+#[cfg(feature = "use-openssl")]
+impl AsTcp for tokio_openssl::SslStream<Timed<TcpStream>> {}
+
+#[cfg(feature = "use-openssl")]
+impl ClientStream for tokio_openssl::SslStream<Timed<TcpStream>> {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.get_mut().set_read_timeout(timeout);
+    }
 }
 
 impl FlowgateServer {
+    /// Creates server with runtime of `threadpool_size` worker threads
     pub fn new(config: Arc<RwLock<Config>>) -> Self {
-        FlowgateServer { config }
+        let runtime = Builder::new_multi_thread()
+            .worker_threads(config.read().unwrap().threadpool_size)
+            .thread_name("flowgate-worker")
+            .enable_all()
+            .build()
+            .unwrap();
+
+        STATS.runtime(runtime.handle().clone());
+
+        FlowgateServer { config, runtime, shutdown: watch::Sender::new(false) }
     }
 
     pub fn start(&self) {
-        thread::spawn({
-            let config = Arc::clone(&self.config);
-            
-            move || {
-                Self::run_http(config)
+        let listeners = self.config.read().unwrap().listeners.clone();
+
+        self.runtime.spawn(async {
+            let mut interval = tokio::time::interval(Duration::from_secs(1));
+            loop {
+                interval.tick().await;
+                upstream::expire_idle();
             }
         });
 
-        thread::spawn({
+        for listener_config in listeners {
             let config = Arc::clone(&self.config);
-            
-            move || {
-                Self::run_https(config)
-            }
-        });
+            let shutdown = self.shutdown.subscribe();
+
+            // Bound before returning, so sockets inherited on upgrade are taken
+            let listener = match upgrade::bind(&listener_config.host).and_then(|o| o.set_nonblocking(true).map(|_| o)) {
+                Ok(listener) => listener,
+                Err(e) => {
+                    error!("Can't listen on {}: {e}", listener_config.host);
+                    continue;
+                }
+            };
+
+            self.runtime.spawn(async move {
+                if listener_config.tls {
+                    Self::run_https(config, listener_config, listener, shutdown).await
+                } else {
+                    Self::run_http(config, listener_config, listener, shutdown).await
+                }
+            });
+        }
     }
 
-    pub fn run_http(
-        config: Arc<RwLock<Config>>
+    /// Stops accepting connections, closes idle keep-alive ones and waits up to `timeout`
+    /// for requests in progress, then drops the rest
+    pub fn shutdown(self, timeout: Duration) {
+        let start = std::time::Instant::now();
+        let requests = STATS.requests.load(Ordering::Relaxed);
+        let active = STATS.active_connections.load(Ordering::Relaxed);
+
+        info!("Shutting down, waiting up to {}s for {active} connections", timeout.as_secs());
+        let _ = self.shutdown.send(true);
+
+        while STATS.active_connections.load(Ordering::Relaxed) > 0 && start.elapsed() < timeout {
+            std::thread::sleep(Duration::from_millis(50));
+        }
+
+        let dropped = STATS.active_connections.load(Ordering::Relaxed);
+        self.runtime.shutdown_background();
+
+        info!(
+            "Stopped in {:.1}s: {} connections drained, {dropped} dropped, {} requests served while draining",
+            start.elapsed().as_secs_f64(),
+            active.saturating_sub(dropped),
+            STATS.requests.load(Ordering::Relaxed) - requests
+        );
+    }
+
+    pub async fn run_http(
+        config: Arc<RwLock<Config>>,
+        listener_config: ListenerConfig,
+        listener: std::net::TcpListener,
+        mut shutdown: watch::Receiver<bool>
     ) -> Option<()> {
-        let listener = TcpListener::bind(&config.read().ok()?.http_host).ok()?;
+        let listener = TcpListener::from_std(listener).ok()?;
 
-        let pool = ThreadPool::new(10);
+        info!("HTTP server runned on {}", &listener_config.host);
 
-        info!("HTTP server runned on {}", &config.read().ok()?.http_host);
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = shutdown.changed() => break
+            };
+            let Some((stream, addr, connection)) = Self::admit(&config, accepted, false) else { continue };
 
-        for stream in listener.incoming() {
-            pool.execute({
+            tokio::spawn({
                 let config = config.clone();
+                let listener_config = listener_config.clone();
+                let shutdown = shutdown.clone();
 
-                move || {
-                    let Ok(mut stream) = stream else { return };
-
-                    let Ok(_) = stream.set_write_timeout(Some(Duration::from_secs(10))) else { return };
-                    let Ok(_) = stream.set_read_timeout(Some(Duration::from_secs(10))) else { return };
+                async move {
+                    let _connection = connection;
 
-                    let Ok(addr) = stream.peer_addr() else { return };
+                    let stream = Self::timed(&config, stream);
 
                     Self::accept_stream(
                         config,
-                        &mut stream,
+                        stream,
                         addr,
-                        false
-                    );
+                        &listener_config,
+                        shutdown
+                    ).await;
                 }
             });
         }
@@ -76,54 +174,75 @@ impl FlowgateServer {
     }
 
     #[cfg(feature = "use-openssl")]
-    pub fn run_https(
-        config: Arc<RwLock<Config>>
+    pub async fn run_https(
+        config: Arc<RwLock<Config>>,
+        listener_config: ListenerConfig,
+        listener: std::net::TcpListener,
+        mut shutdown: watch::Receiver<bool>
     ) -> Option<()> {
-        use openssl::ssl::{NameType, SniError, SslAcceptor, SslAlert, SslMethod, SslRef};
+        use openssl::ssl::{select_next_proto, AlpnError, NameType, SniError, Ssl, SslAcceptor, SslAlert, SslMethod, SslRef};
+        use tokio_openssl::SslStream;
 
-        let listener = TcpListener::bind(&config.read().ok()?.https_host).ok()?;
+        let listener = TcpListener::from_std(listener).ok()?;
 
         let mut cert = SslAcceptor::mozilla_intermediate(SslMethod::tls()).ok()?;
 
         cert.set_servername_callback(Box::new({
                 let config = config.clone();
+                let listener_config = listener_config.clone();
 
                 move |ssl: &mut SslRef, _: &mut SslAlert| -> Result<(), SniError> {
                     let servname = ssl.servername(NameType::HOST_NAME).ok_or(SniError::NOACK)?;
                     let c = config.read().unwrap();
-                    let cert = c.get_site(servname).ok_or(SniError::NOACK)?;
-                    ssl.set_ssl_context(&cert.ssl.as_ref().ok_or(SniError::NOACK)?.get_context()).ok().ok_or(SniError::NOACK)
+                    let cert = c.get_listener_site(&listener_config, servname).ok_or(SniError::NOACK)?;
+                    cert.ssl.as_ref().ok_or(SniError::NOACK)?.apply(ssl).ok_or(SniError::NOACK)
                 }
             }
         ));
 
+        if config.read().ok()?.http2.is_some() {
+            cert.set_alpn_select_callback(|_, client| {
+                select_next_proto(b"\x02h2\x08http/1.1", client).ok_or(AlpnError::NOACK)
+            });
+        }
+
         let cert = cert.build();
 
-        let pool = ThreadPool::new(config.read().ok()?.threadpool_size);
+        info!("HTTPS server runned on {}", &listener_config.host);
 
-        info!("HTTPS server runned on {}", &config.read().ok()?.https_host);
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = shutdown.changed() => break
+            };
+            let Some((stream, addr, connection)) = Self::admit(&config, accepted, true) else { continue };
 
-        for stream in listener.incoming() {
-            pool.execute({
+            tokio::spawn({
                 let config = config.clone();
+                let listener_config = listener_config.clone();
+                let shutdown = shutdown.clone();
                 let cert = cert.clone();
 
-                move || {
-                    let Ok(stream) = stream else { return };
-                    
-                    let Ok(_) = stream.set_write_timeout(Some(config.read().unwrap().connection_timeout)) else { return };
-                    let Ok(_) = stream.set_read_timeout(Some(config.read().unwrap().connection_timeout)) else { return };
+                async move {
+                    let _connection = connection;
 
-                    let Ok(addr) = stream.peer_addr() else { return };
+                    let Ok(ssl) = Ssl::new(cert.context()) else { return };
+                    let Ok(mut stream) = SslStream::new(ssl, Self::timed(&config, stream)) else { return };
+                    let Ok(_) = Pin::new(&mut stream).accept().await else { return };
 
-                    let Ok(mut stream) = cert.accept(stream) else { return };
+                    if stream.ssl().selected_alpn_protocol() == Some(b"h2") {
+                        stream.set_read_timeout(None);
+                        http2::serve(config, &listener_config, stream, addr, shutdown).await;
+                        return;
+                    }
 
                     Self::accept_stream(
                         config,
-                        &mut stream,
+                        stream,
                         addr,
-                        true
-                    );
+                        &listener_config,
+                        shutdown
+                    ).await;
                 }
             });
         }
@@ -132,19 +251,22 @@ impl FlowgateServer {
     }
 
     #[cfg(feature = "use-rustls")]
-    pub fn run_https(
-        config: Arc<RwLock<Config>>
+    pub async fn run_https(
+        config: Arc<RwLock<Config>>,
+        listener_config: ListenerConfig,
+        listener: std::net::TcpListener,
+        mut shutdown: watch::Receiver<bool>
     ) -> Option<()> {
         use std::sync::Arc;
         use rustls::{server::ResolvesServerCertUsingSni, ServerConfig};
         use super::ssl_cert::AdoptedConnection;
 
-        let listener = TcpListener::bind(&config.https_host).ok()?;
+        let listener = TcpListener::from_std(listener).ok()?;
 
         let mut cert_resolver = ResolvesServerCertUsingSni::new();
 
         for site in config.sites.iter() {
-            if let Some(cert) = site.ssl {
+            if let (Some(cert), true) = (site.ssl, listener_config.serves(&site.domain)) {
                 cert_resolver.add(&site.domain, cert.get_certified_key());
             }
         }
@@ -155,31 +277,33 @@ impl FlowgateServer {
             .with_cert_resolver(Arc::new(cert_resolver))
         );
 
-        let pool = ThreadPool::new(10);
+        info!("HTTPS server runned on {}", &listener_config.host);
 
-        info!("HTTPS server runned on {}", &config.https_host);
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = shutdown.changed() => break
+            };
+            let Some((stream, addr, connection)) = Self::admit(&config, accepted, true) else { continue };
 
-        for stream in listener.incoming() {
-            pool.execute({
+            tokio::spawn({
                 let config = config.clone();
+                let listener_config = listener_config.clone();
+                let shutdown = shutdown.clone();
                 let tls_config = tls_config.clone();
 
-                move || {
-                    let Ok(mut stream) = stream else { return };
-
-                    let Ok(_) = stream.set_write_timeout(Some(Duration::from_secs(10))) else { return };
-                    let Ok(_) = stream.set_read_timeout(Some(Duration::from_secs(10))) else { return };
-
-                    let Ok(addr) = stream.peer_addr() else { return };
+                async move {
+                    let _connection = connection;
 
-                    let Some(mut stream) = AdoptedConnection::from_config(tls_config, stream) else { return };
+                    let Some(stream) = AdoptedConnection::from_config(tls_config, Self::timed(&config, stream)) else { return };
 
                     Self::accept_stream(
                         config,
-                        &mut stream,
+                        stream,
                         addr,
-                        true
-                    );
+                        &listener_config,
+                        shutdown
+                    ).await;
                 }
             });
         }
@@ -187,72 +311,77 @@ impl FlowgateServer {
         Some(())
     }
 
-    pub fn accept_stream(
-        config: Arc<RwLock<Config>>, 
-        stream: &mut (impl Read + Write + Closeable), 
+    pub async fn accept_stream(
+        config: Arc<RwLock<Config>>,
+        stream: impl ClientStream,
         addr: SocketAddr,
-        https: bool
+        listener: &ListenerConfig,
+        mut shutdown: watch::Receiver<bool>
     ) -> Option<()> {
-        let mut conn = Self::read_request(config.clone(), stream, addr, https, None)?;
+        let mut stream = BufReader::new(stream);
 
-        if conn.keep_alive && conn.config.enable_keep_alive {
-            loop {
-                if !conn.config.support_keep_alive {
-                    conn.stream.close();
-                    conn.stream = conn.config.connect()?;
-                }
-                conn = Self::read_request(config.clone(), stream, addr, https, Some(conn))?;
+        if !Self::wait_request(&mut stream, &mut shutdown).await {
+            return None;
+        }
+
+        let mut conn = Self::read_request(config.clone(), &mut stream, addr, listener, None).await?;
+
+        while conn.keep_alive && conn.config.enable_keep_alive {
+            if let Some(timeout) = conn.config.idle_timeout {
+                stream.get_mut().set_read_timeout(Some(timeout));
             }
+            if !Self::wait_request(&mut stream, &mut shutdown).await {
+                break;
+            }
+            conn = Self::read_request(config.clone(), &mut stream, addr, listener, Some(conn)).await?;
         }
 
-        conn.stream.close();
-        stream.close();
+        let _ = stream.shutdown().await;
 
         Some(())
     }
 
-    fn read_request<'a>(
-        config: Arc<RwLock<Config>>, 
-        stream: &'a mut (impl Read + Write + Closeable), 
+    /// Waits for the next request on client connection.
+    /// Returns `false` if the connection was closed or timed out, or the server is shutting down
+    async fn wait_request(stream: &mut BufReader<impl ClientStream>, shutdown: &mut watch::Receiver<bool>) -> bool {
+        if *shutdown.borrow() {
+            return false;
+        }
+
+        tokio::select! {
+            result = stream.fill_buf() => result.is_ok_and(|o| !o.is_empty()),
+            _ = shutdown.changed() => false
+        }
+    }
+
+    async fn read_request(
+        config: Arc<RwLock<Config>>,
+        stream: &mut BufReader<impl ClientStream>,
         addr: SocketAddr,
-        https: bool,
+        listener: &ListenerConfig,
         conn: Option<Connection>
     ) -> Option<Connection> {
         let mut addr = addr;
 
-        match &config.read().ok()?.incoming_ip_forwarding {
+        match &listener.incoming_ip_forwarding {
             IpForwarding::Simple => {
                 let mut header = Vec::new();
-
-                {
-                    let mut buf = [0; 1];
-
-                    while let Ok(1) = stream.read(&mut buf) {
-                        let byte = buf[0];
-                        if byte == b'\n' { break }
-                        header.push(byte);
-                    }
-                }
+                stream.read_until(b'\n', &mut header).await.ok()?;
+                if header.last() == Some(&b'\n') { header.pop(); }
 
                 addr = SocketAddr::from_str(&String::from_utf8(header).ok()?).ok()?;
             },
             IpForwarding::Modern => {
-                let mut ipver = [0; 1];
-                stream.read(&mut ipver).ok()?;
-                addr = match ipver[0] {
+                addr = match stream.read_u8().await.ok()? {
                     0x01 => {
                         let mut octets = [0; 4];
-                        stream.read(&mut octets).ok()?;
-                        let mut port = [0; 2];
-                        stream.read(&mut port).ok()?;
-                        let port = u16::from_be_bytes(port);
+                        stream.read_exact(&mut octets).await.ok()?;
+                        let port = stream.read_u16().await.ok()?;
                         SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::from(octets), port))
                     }, 0x02 => {
                         let mut octets = [0; 16];
-                        stream.read(&mut octets).ok()?;
-                        let mut port = [0; 2];
-                        stream.read(&mut port).ok()?;
-                        let port = u16::from_be_bytes(port);
+                        stream.read_exact(&mut octets).await.ok()?;
+                        let port = stream.read_u16().await.ok()?;
                         SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::from(octets), port, 0, 0))
                     }, _ => { return None },
                 };
@@ -260,30 +389,16 @@ impl FlowgateServer {
             _ => { }
         }
 
-        let mut head = Vec::new();
-
-        {
-            let mut buf = [0; 1];
-            let mut counter = 0;
-
-            while let Ok(1) = stream.read(&mut buf) {
-                let byte = buf[0];
-                head.push(byte);
+        let mut head = Self::read_head(stream).await.ok()?;
+        head.truncate(head.len().saturating_sub(4));
 
-                counter = match (counter, byte) {
-                    (0, b'\r') => 1,
-                    (1, b'\n') => 2,
-                    (2, b'\r') => 3,
-                    (3, b'\n') => break,
-                    _ => 0,
-                };
-            }
+        if head.is_empty() { return None; }
 
-            head.truncate(head.len() - 4);
+        if conn.as_ref().is_some_and(|o| o.config.idle_timeout.is_some()) {
+            let timeout = config.read().ok()?.connection_timeout;
+            stream.get_mut().set_read_timeout(Some(timeout));
         }
 
-        if head.is_empty() { return None; }
-        
         let head_str = String::from_utf8(head.clone()).ok()?;
         let head_str = head_str.trim_matches(char::from(0)).to_string();
 
@@ -302,13 +417,21 @@ impl FlowgateServer {
             .map(|o| o.1.split(",").map(|x| x.trim_matches(' ').to_string()).collect::<Vec<String>>())
             .map(|o| o.contains(&"chunked".to_string()))
             .unwrap_or(false);
-        
-        if let IpForwarding::Header(header) = &config.read().ok()?.incoming_ip_forwarding {
+
+        if let IpForwarding::Header(header) = &listener.incoming_ip_forwarding {
             if let Some(ip) = headers.iter().find(|o| o.0 == header).map(|o| o.1) {
                 addr = SocketAddr::from_str(ip).ok()?;
             }
         }
 
+        if status_seq[0] == "CONNECT" && !listener.tls {
+            if let Some(proxy) = &listener.forward_proxy {
+                let timeout = config.read().ok()?.connection_timeout;
+                Self::accept_tunnel(proxy, timeout, stream, &headers, status_seq.get(1)?, addr).await;
+                return None;
+            }
+        }
+
         let mut conn: Connection = if conn.is_none() {
             let mut host = String::new();
             let mut keep_alive = false;
@@ -321,29 +444,306 @@ impl FlowgateServer {
                 }
             }
 
-            let site = config.read().ok()?.get_site(&host)?.clone();
+            let site = config.read().ok()?.get_listener_site(listener, &host)?.clone();
 
             Connection {
-                stream: site.connect()?,
                 config: site,
                 keep_alive,
-                host
+                host,
+                buffer: Vec::new()
             }
         } else {
             conn?
         };
-        
+
+        if conn.config.upstream_protocol != UpstreamProtocol::Http1 {
+            let keep_alive = conn.keep_alive && conn.config.enable_keep_alive;
+            let keep_alive = http2::forward_http1(&conn.config, stream, &head_str, keep_alive, addr).await?;
+            STATS.request();
+            info!("{addr} > {} {}://{}{}", status_seq[0], if listener.tls { "https" } else { "http" }, conn.host, status_seq[1]);
+            return keep_alive.then_some(conn);
+        }
+
+        let deadline = conn.config.request_timeout.map(|o| Instant::now() + o);
+        let zero_copy = config.read().ok()?.zero_copy;
+        let expired = || deadline.is_some_and(|o| Instant::now() >= o);
+
         let content_length = headers
             .iter()
-            .filter(|(k, _)| k.to_lowercase() == "content-length")
-            .next()
-            .map(|o| o.1.parse().ok())
-            .flatten()
+            .find(|(k, _)| k.to_lowercase() == "content-length")
+            .and_then(|o| o.1.parse().ok())
             .unwrap_or(0usize);
 
+        let reqbuf = Self::build_request(&conn.config, &head_str, addr)?;
+
+        // Pooled connection can be closed by the server at any moment,
+        // so idempotent requests without body are sent again over a new connection then
+        let idempotent = matches!(status_seq[0], "GET" | "HEAD" | "OPTIONS" | "TRACE" | "PUT" | "DELETE");
+        let mut reuse = true;
+        let mut socket;
+
+        let (mut upstream, head, reused) = loop {
+            // Stale connection gives its slot back before a new one is opened
+            socket = None;
+            let (new, reused) = match Self::connect(&conn.config, reuse).await {
+                Some(o) => o,
+                None => {
+                    let status = if conn.config.is_full() { "503 Service Unavailable" } else { "502 Bad Gateway" };
+                    Self::respond_error(stream, status).await;
+                    info!("{addr} > {} {}://{}{} ({status})", status_seq[0], if listener.tls { "https" } else { "http" }, conn.host, status_seq[1]);
+                    return None;
+                }
+            };
+            let mut upstream = Self::upstream(&conn.config, socket.insert(new));
+
+            let retry = reused && idempotent && content_length == 0 && !is_chunked;
+            reuse = false;
+
+            if upstream.write_all(&reqbuf).await.is_err() {
+                if retry { continue }
+                Self::respond_error(stream, "502 Bad Gateway").await;
+                return None;
+            }
+
+            if content_length > 0 {
+                let result = forward::forward(&mut *stream, &mut upstream, Some(content_length), &mut conn.buffer, zero_copy, deadline).await;
+                if result.is_err() && !expired() { return None }
+            } else if is_chunked {
+                loop {
+                    let mut length = Vec::new();
+                    stream.read_until(b'\n', &mut length).await.ok()?;
+                    upstream.write_all(&length).await.ok()?;
+                    length.truncate(length.len().saturating_sub(2));
+
+                    let length = usize::from_str_radix(String::from_utf8(length).ok()?.as_str(), 16).ok()?;
+                    let mut data = vec![0; length+2];
+                    stream.read_exact(&mut data).await.ok()?;
+                    upstream.write_all(&data).await.ok()?;
+                    if length == 0 || expired() {
+                        break;
+                    }
+                }
+            }
+
+            if expired() {
+                Self::respond_error(stream, "504 Gateway Timeout").await;
+                return None;
+            }
+
+            if !conn.config.support_keep_alive { break (upstream, None, reused) }
+
+            // Waiting for the response is limited by time left for the request
+            let head = match deadline {
+                Some(deadline) => tokio::time::timeout_at(deadline, Self::read_head(&mut upstream)).await
+                    .unwrap_or(Err(io::ErrorKind::TimedOut.into())),
+                None => Self::read_head(&mut upstream).await
+            };
+
+            let timed_out = head.as_ref().is_err_and(|e| matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut));
+
+            match head {
+                Ok(head) if !head.is_empty() => break (upstream, Some(head), reused),
+                _ if retry && !timed_out => continue,
+                _ => {
+                    Self::respond_error(stream, if timed_out { "504 Gateway Timeout" } else { "502 Bad Gateway" }).await;
+                    return None;
+                }
+            }
+        };
+
+        if reused {
+            STATS.reuse();
+        }
+
+        if let Some(mut head) = head {
+            stream.write_all(&head).await.ok()?;
+            head.truncate(head.len().saturating_sub(4));
+
+            let head_str = String::from_utf8(head.clone()).ok()?;
+            let head_str = head_str.trim_matches(char::from(0));
+
+            let response_status: u16 = head_str.split(' ').nth(1).and_then(|o| o.parse().ok()).unwrap_or(0);
+
+            let response_headers: Vec<(&str, &str)> = head_str.split("\r\n")
+                .skip(1)
+                .filter(|l| l.contains(": "))
+                .map(|l| l.split_once(": ").unwrap())
+                .collect();
+
+            let content_length: Option<usize> = response_headers.iter()
+                .find(|(k, _)| k.to_lowercase() == "content-length")
+                .and_then(|o| o.1.parse().ok());
+
+            let chunked = response_headers.iter()
+                .any(|(k, v)| k.to_lowercase() == "transfer-encoding" && v.split(',').any(|o| o.trim() == "chunked"));
+
+            let has_body = status_seq[0] != "HEAD" && !matches!(response_status, 100..=199 | 204 | 304);
+
+            if has_body && chunked {
+                Self::forward_chunked(&mut upstream, &mut *stream, &mut conn.buffer, zero_copy, deadline).await?;
+            } else if has_body && content_length.is_some_and(|o| o > 0) {
+                let read = forward::forward(&mut upstream, &mut *stream, content_length, &mut conn.buffer, zero_copy, deadline).await.ok()?;
+                if Some(read) < content_length { return None }
+            } else if has_body && content_length.is_none() {
+                // Body ends when the server closes the connection, so the client can't tell its end otherwise
+                forward::forward(&mut upstream, &mut *stream, None, &mut conn.buffer, zero_copy, deadline).await.ok()?;
+                conn.keep_alive = false;
+            }
+
+            // Connection is reused only if the response end is known and nothing is left unread
+            let complete = (!has_body || chunked || content_length.is_some())
+                && !matches!(response_status, 100..=199)
+                && upstream.buffer().is_empty()
+                && !response_headers.iter().any(|(k, v)| k.to_lowercase() == "connection" && v.to_lowercase() == "close");
+
+            if complete {
+                drop(upstream);
+                conn.config.release(socket?);
+            }
+        } else {
+            forward::forward(&mut upstream, &mut *stream, None, &mut conn.buffer, zero_copy, deadline).await.ok()?;
+        }
+
+        stream.flush().await.ok()?;
+
+        STATS.request();
+        info!("{addr} > {} {}://{}{}", status_seq[0], if listener.tls { "https" } else { "http" }, conn.host, status_seq[1]);
+
+        Some(conn)
+    }
+
+    /// Forwards chunked body up to the last chunk and its trailers
+    async fn forward_chunked<R, W>(
+        from: &mut BufReader<R>,
+        to: &mut W,
+        buf: &mut Vec<u8>,
+        zero_copy: bool,
+        deadline: Option<Instant>
+    ) -> Option<()>
+    where
+        R: AsyncRead + AsTcp + Unpin,
+        W: AsyncWrite + AsTcp + Unpin
+    {
+        loop {
+            let mut line = String::new();
+            if from.read_line(&mut line).await.ok()? == 0 { return None }
+            to.write_all(line.as_bytes()).await.ok()?;
+
+            let length = usize::from_str_radix(line.split(';').next()?.trim(), 16).ok()?;
+            if length == 0 { break }
+
+            // Chunk data is followed by CRLF
+            let read = forward::forward(from, to, Some(length + 2), buf, zero_copy, deadline).await.ok()?;
+            if read < length + 2 { return None }
+        }
+
+        // Trailers end with an empty line
+        loop {
+            let mut line = String::new();
+            if from.read_line(&mut line).await.ok()? == 0 { return None }
+            to.write_all(line.as_bytes()).await.ok()?;
+            if line.trim_end().is_empty() { return Some(()) }
+        }
+    }
+
+    /// Reads message head up to the empty line, which is kept.
+    /// Error is returned only if nothing was read
+    async fn read_head(stream: &mut (impl AsyncRead + Unpin)) -> io::Result<Vec<u8>> {
+        let mut head = Vec::new();
+        let mut counter = 0;
+
+        loop {
+            let byte = match stream.read_u8().await {
+                Ok(byte) => byte,
+                Err(e) if head.is_empty() && e.kind() != io::ErrorKind::UnexpectedEof => return Err(e),
+                Err(_) => break
+            };
+            head.push(byte);
+
+            counter = match (counter, byte) {
+                (0, b'\r') => 1,
+                (1, b'\n') => 2,
+                (2, b'\r') => 3,
+                (3, b'\n') => break,
+                _ => 0,
+            };
+        }
+
+        Ok(head)
+    }
+
+    /// Takes idle connection to the site server from the pool if `reuse`, or connects a new one.
+    /// Returns whether connection was reused
+    pub async fn connect(site: &SiteConfig, reuse: bool) -> Option<(AsyncUpstreamStream, bool)> {
+        match if reuse { site.reuse() } else { None } {
+            Some(stream) => Some((stream, true)),
+            None => Some((site.connect_async().await?, false))
+        }
+    }
+
+    /// Sets `upstream_timeout` for reads and writes of connection to the site server
+    pub fn upstream<'a>(site: &SiteConfig, stream: &'a mut AsyncUpstreamStream) -> Upstream<'a> {
+        let mut stream = TimeoutStream::new(stream);
+        stream.set_read_timeout(site.upstream_timeout);
+        stream.set_write_timeout(site.upstream_timeout);
+        BufReader::new(Box::pin(stream))
+    }
+
+    /// Sets `connection_timeout` for reads and writes of client connection.
+    /// Responses are written in parts, so Nagle's algorithm is disabled
+    fn timed(config: &Arc<RwLock<Config>>, stream: TcpStream) -> Timed<TcpStream> {
+        let timeout = config.read().unwrap().connection_timeout;
+        let _ = stream.set_nodelay(true);
+        let mut stream = TimeoutStream::new(stream);
+        stream.set_read_timeout(Some(timeout));
+        stream.set_write_timeout(Some(timeout));
+        Box::pin(stream)
+    }
+
+    /// Counts accepted connection as active unless `connection_limit` connections are served already.
+    /// Rejected plain HTTP connections get 503, TLS ones are closed
+    fn admit(
+        config: &Arc<RwLock<Config>>,
+        accepted: io::Result<(TcpStream, SocketAddr)>,
+        tls: bool
+    ) -> Option<(TcpStream, SocketAddr, ConnectionGuard)> {
+        let (mut stream, addr) = accepted.ok()?;
+
+        let limit = config.read().ok()?.connection_limit;
+
+        let Some(connection) = STATS.connection(limit) else {
+            STATS.reject();
+            if !tls {
+                tokio::spawn(async move {
+                    let _ = tokio::time::timeout(Duration::from_secs(1), async {
+                        Self::respond_error(&mut stream, "503 Service Unavailable").await;
+                        let _ = stream.shutdown().await;
+                    }).await;
+                });
+            }
+            return None;
+        };
+
+        Some((stream, addr, connection))
+    }
+
+    /// Sends empty response with `status` before closing the connection
+    pub async fn respond_error(stream: &mut (impl AsyncWrite + Unpin), status: &str) {
+        let _ = stream.write_all(format!("HTTP/1.1 {status}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").as_bytes()).await;
+    }
+
+    /// Builds request to the site server from the request head (without trailing CRLFs),
+    /// applying host replacement and ip forwarding
+    pub fn build_request(
+        site: &SiteConfig,
+        head_str: &str,
+        addr: SocketAddr
+    ) -> Option<Vec<u8>> {
+        let mut head = head_str.as_bytes().to_vec();
+        let status = head_str.split("\r\n").next()?;
         let mut reqbuf: Vec<u8> = Vec::new();
 
-        if let Some(replace_host) = conn.config.replace_host.clone() {
+        if let Some(replace_host) = site.replace_host.clone() {
             let mut new_head = Vec::new();
             let mut is_status = true;
 
@@ -367,7 +767,7 @@ impl FlowgateServer {
             head = new_head;
         }
 
-        match &conn.config.ip_forwarding {
+        match &site.ip_forwarding {
             IpForwarding::Header(header) => {
                 reqbuf.append(&mut status.to_string().as_bytes().to_vec());
                 reqbuf.append(&mut b"\r\n".to_vec());
@@ -386,136 +786,84 @@ impl FlowgateServer {
                 reqbuf.append(&mut addr.to_string().as_bytes().to_vec());
                 reqbuf.append(&mut b"\r\n\r\n".to_vec());
             },
-            IpForwarding::Simple => {
-                reqbuf.append(&mut addr.to_string().as_bytes().to_vec());
-                reqbuf.push(b'\n');
+            _ => {
+                reqbuf.append(&mut Self::forwarding_prefix(&site.ip_forwarding, addr));
                 reqbuf.append(&mut head.clone());
                 reqbuf.append(&mut b"\r\n\r\n".to_vec());
+            }
+        }
+
+        Some(reqbuf)
+    }
+
+    /// Returns bytes sent before the request for `simple` and `modern` ip forwarding
+    pub fn forwarding_prefix(ip_forwarding: &IpForwarding, addr: SocketAddr) -> Vec<u8> {
+        let mut prefix = Vec::new();
+
+        match ip_forwarding {
+            IpForwarding::Simple => {
+                prefix.append(&mut addr.to_string().as_bytes().to_vec());
+                prefix.push(b'\n');
             },
             IpForwarding::Modern => {
-                reqbuf.push(if addr.is_ipv4() { 0x01 } else { 0x02 });
+                prefix.push(if addr.is_ipv4() { 0x01 } else { 0x02 });
                 match addr.ip() {
                     IpAddr::V4(ip) => {
-                        reqbuf.append(&mut ip.octets().to_vec());
+                        prefix.append(&mut ip.octets().to_vec());
                     }, IpAddr::V6(ip) => {
-                        reqbuf.append(&mut ip.octets().to_vec());
+                        prefix.append(&mut ip.octets().to_vec());
                     }
                 }
-                reqbuf.append(&mut addr.port().to_be_bytes().to_vec());
-                reqbuf.append(&mut head.clone());
-                reqbuf.append(&mut b"\r\n\r\n".to_vec());
+                prefix.append(&mut addr.port().to_be_bytes().to_vec());
             },
-            IpForwarding::None => {
-                reqbuf.append(&mut head.clone());
-                reqbuf.append(&mut b"\r\n\r\n".to_vec());
-            }
+            _ => {}
         }
 
-        conn.stream.write_all(&reqbuf).ok()?;
-
-        if content_length > 0 {
-            let mut read = 0usize;
-            let mut buf = vec![0; 4096];
-            while let Ok(size) = stream.read(&mut buf) {
-                if size == 0 { break }
-                read += size;
-                buf.truncate(size);
-                conn.stream.write_all(&buf).ok()?;
-                buf = vec![0; 4096];
-                if read >= content_length { break }
-            }
-        } else if is_chunked {
-            loop {
-                let mut length = Vec::new();
-                {
-                    let mut buf = [0; 1];
-                    let mut counter = 0;
-
-                    while let Ok(1) = stream.read(&mut buf) {
-                        let byte = buf[0];
-                        length.push(byte);
-
-                        counter = match (counter, byte) {
-                            (0, b'\r') => 1,
-                            (1, b'\n') => break,
-                            _ => 0,
-                        };
-                        conn.stream.write_all(&buf).ok()?;
-                    }
+        prefix
+    }
 
-                    length.truncate(length.len() - 2);
-                }
-                let length = usize::from_str_radix(String::from_utf8(length).ok()?.as_str(), 16).ok()?;
-                let mut data = vec![0; length+2];
-                stream.read_exact(&mut data).ok()?;
-                conn.stream.write_all(&data).ok()?;
-                data.truncate(length);
-                if length == 0 {
-                    break;
-                }
+    async fn accept_tunnel(
+        proxy: &ForwardProxyConfig,
+        timeout: Duration,
+        stream: &mut BufReader<impl ClientStream>,
+        headers: &[(&str, &str)],
+        target: &str,
+        addr: SocketAddr
+    ) -> Option<()> {
+        if let Some(auth) = &proxy.auth {
+            let authorized = headers.iter()
+                .find(|o| o.0.to_lowercase() == "proxy-authorization")
+                .and_then(|o| o.1.strip_prefix("Basic "))
+                .and_then(|o| BASE64_STANDARD.decode(o.trim()).ok())
+                .and_then(|o| String::from_utf8(o).ok())
+                .is_some_and(|o| token_eq(&o, auth));
+
+            if !authorized {
+                stream.write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: Basic realm=\"flowgate\"\r\nContent-Length: 0\r\n\r\n").await.ok()?;
+                return None;
             }
         }
 
-        if conn.config.support_keep_alive {
-            let mut head = Vec::new();
-
-            {
-                let mut buf = [0; 1];
-                let mut counter = 0;
-
-                while let Ok(1) = conn.stream.read(&mut buf) {
-                    let byte = buf[0];
-                    head.push(byte);
-
-                    stream.write_all(&buf).ok()?;
-
-                    counter = match (counter, byte) {
-                        (0, b'\r') => 1,
-                        (1, b'\n') => 2,
-                        (2, b'\r') => 3,
-                        (3, b'\n') => break,
-                        _ => 0,
-                    };
-                }
+        if !proxy.is_allowed(target) {
+            info!("{addr} > CONNECT {target} (denied)");
+            stream.write_all(b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n").await.ok()?;
+            return None;
+        }
 
-                head.truncate(head.len() - 4);
-            }
+        let Ok(Ok(mut upstream)) = tokio::time::timeout(timeout, TcpStream::connect(target)).await else {
+            stream.write_all(b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\n\r\n").await.ok()?;
+            return None;
+        };
 
-            if head.is_empty() { return None; }
-            
-            let head_str = String::from_utf8(head.clone()).ok()?;
-            let head_str = head_str.trim_matches(char::from(0));
+        stream.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n").await.ok()?;
 
-            let content_length = head_str.split("\r\n")
-                .skip(1)
-                .filter(|l| l.contains(": "))
-                .map(|l| l.split_once(": ").unwrap())
-                .filter(|(k, _)| k.to_lowercase() == "content-length")
-                .next()
-                .map(|o| o.1.parse().ok())
-                .flatten()
-                .unwrap_or(0usize);
+        STATS.request();
+        info!("{addr} > CONNECT {target}");
 
-            if content_length > 0 {
-                let mut read = 0usize;
-                let mut buf = vec![0; 4096];
-                while let Ok(size) = conn.stream.read(&mut buf) {
-                    if size == 0 { break }
-                    read += size;
-                    buf.truncate(size);
-                    stream.write_all(&buf).ok()?;
-                    buf = vec![0; 4096];
-                    if read == content_length { break }
-                }
-            }
-        } else {
-            let mut buf = Vec::new();
-            conn.stream.read_to_end(&mut buf).ok()?;
-            stream.write_all(&buf).ok()?;
-        }
+        stream.get_mut().set_read_timeout(None);
 
-        info!("{addr} > {} {}://{}{}", status_seq[0], if https { "https" } else { "http" }, conn.host, status_seq[1]);
+        tokio::io::copy_bidirectional(stream, &mut upstream).await.ok()?;
 
-        Some(conn)
+        Some(())
     }
-}
\ No newline at end of file
+}
@@ -1,33 +1,63 @@
 #[cfg(feature = "use-openssl")]
-use openssl::ssl::SslContext;
+use openssl::{pkey::{PKey, Private}, ssl::SslRef, x509::X509};
 
 #[cfg(feature = "use-openssl")]
 #[derive(Clone)]
 pub struct SslCert {
-    context: SslContext,
+    certs: Vec<X509>,
+    key: PKey<Private>,
+    source: (String, String)
+}
+
+/// Checks whether certificate or key `source` is inline PEM rather than a file path
+pub fn is_pem(source: &str) -> bool {
+    source.trim_start().starts_with("-----BEGIN")
+}
+
+/// Reads PEM from file, or takes `source` itself if it is inline PEM
+fn read_pem(source: &str) -> Option<Vec<u8>> {
+    if is_pem(source) {
+        Some(source.as_bytes().to_vec())
+    } else {
+        std::fs::read(source).ok()
+    }
 }
 
 #[cfg(feature = "use-openssl")]
-fn generate_ctx(cert_file: &str, key_file: &str) -> Option<SslContext> {
-    use openssl::ssl::{SslFiletype, SslMethod};
-
-    let mut ctx = SslContext::builder(SslMethod::tls()).ok()?;
-    ctx.set_private_key_file(&key_file, SslFiletype::PEM).ok()?;
-    ctx.set_certificate_file(&cert_file, SslFiletype::PEM).ok()?;
-    ctx.check_private_key().ok()?;
-    Some(ctx.build())
+fn load_cert_key(cert_file: &str, key_file: &str) -> Option<(Vec<X509>, PKey<Private>)> {
+    let certs = X509::stack_from_pem(&read_pem(cert_file)?).ok()?;
+    let key = PKey::private_key_from_pem(&read_pem(key_file)?).ok()?;
+
+    if !certs.first()?.public_key().ok()?.public_eq(&key) {
+        return None;
+    }
+
+    Some((certs, key))
 }
 
 #[cfg(feature = "use-openssl")]
 impl SslCert {
+    /// Loads certificate chain and private key, 
+    /// each given as a file path or inline PEM
     pub fn new(cert_file: &str, key_file: &str) -> Option<SslCert> {
-        Some(SslCert {
-            context: generate_ctx(cert_file, key_file)?
-        })
+        let (certs, key) = load_cert_key(cert_file, key_file)?;
+        Some(SslCert { certs, key, source: (cert_file.to_string(), key_file.to_string()) })
     }
 
-    pub fn get_context(&self) -> SslContext {
-        self.context.clone()
+    /// Returns certificate and key as they were given to [`SslCert::new`]
+    pub fn source(&self) -> (&str, &str) {
+        (&self.source.0, &self.source.1)
+    }
+
+    /// Sets certificate chain and private key on the connection, 
+    /// keeping acceptor settings (ALPN, ciphers) untouched
+    pub fn apply(&self, ssl: &mut SslRef) -> Option<()> {
+        ssl.set_certificate(self.certs.first()?).ok()?;
+        ssl.set_private_key(&self.key).ok()?;
+        for cert in self.certs.iter().skip(1) {
+            ssl.add_chain_cert(cert.clone()).ok()?;
+        }
+        Some(())
     }
 }
 
@@ -40,19 +70,19 @@ use std::{net::TcpStream, sync::Arc};
 #[derive(Clone)]
 pub struct SslCert {
     cert_key: CertifiedKey,
+    source: (String, String)
 }
 
 #[cfg(feature = "use-rustls")]
 fn generate_cert_key(cert_file: &str, key_file: &str) -> Option<CertifiedKey> {
     use rustls::crypto::CryptoProvider;
-    use std::fs::File;
     use std::io::BufReader;
 
-    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key_file).ok()?)).ok()??;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(read_pem(key_file)?.as_slice())).ok()??;
     let key = CryptoProvider::get_default().unwrap().key_provider.load_private_key(key).ok()?;
 
     let cert = 
-        rustls_pemfile::public_keys(&mut BufReader::new(File::open(cert_file).ok()?))
+        rustls_pemfile::public_keys(&mut BufReader::new(read_pem(cert_file)?.as_slice()))
         .map(|o| o.unwrap().to_vec().into())
         .collect::<Vec<_>>();
     Some(CertifiedKey::new(cert, key))
@@ -63,9 +93,14 @@ impl SslCert {
     pub fn new(cert_file: &str, key_file: &str) -> Option<SslCert> {
         Some(SslCert {
             cert_key: generate_cert_key(cert_file, key_file)?,
+            source: (cert_file.to_string(), key_file.to_string())
         })
     }
 
+    pub fn source(&self) -> (&str, &str) {
+        (&self.source.0, &self.source.1)
+    }
+
     pub fn get_certified_key(&self) -> CertifiedKey {
         self.cert_key.clone()
     }
@@ -0,0 +1,84 @@
+use std::{sync::{atomic::{AtomicUsize, Ordering}, OnceLock}, time::{Duration, Instant}};
+
+use tokio::runtime::Handle;
+
+/// Process-wide counters exposed through control API
+pub struct Stats {
+    pub connections: AtomicUsize,
+    pub active_connections: AtomicUsize,
+    pub requests: AtomicUsize,
+    /// Connections refused because `connection_limit` was reached
+    pub rejected_connections: AtomicUsize,
+    /// Requests sent over idle connections taken from the pool
+    pub reused_connections: AtomicUsize,
+    /// Runtime of the proxy and its start time
+    runtime: OnceLock<(Handle, Instant)>
+}
+
+pub static STATS: Stats = Stats {
+    connections: AtomicUsize::new(0),
+    active_connections: AtomicUsize::new(0),
+    requests: AtomicUsize::new(0),
+    rejected_connections: AtomicUsize::new(0),
+    reused_connections: AtomicUsize::new(0),
+    runtime: OnceLock::new()
+};
+
+/// Keeps connection counted as active until dropped
+pub struct ConnectionGuard;
+
+impl Stats {
+    /// Counts connection as active, unless `limit` connections are active already
+    pub fn connection(&self, limit: Option<usize>) -> Option<ConnectionGuard> {
+        self.active_connections.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |o| {
+            limit.is_none_or(|limit| o < limit).then_some(o + 1)
+        }).ok()?;
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        Some(ConnectionGuard)
+    }
+
+    pub fn request(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn runtime(&self, handle: Handle) {
+        let _ = self.runtime.set((handle, Instant::now()));
+    }
+
+    pub fn reject(&self) {
+        self.rejected_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn reuse(&self) {
+        self.reused_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Worker threads of the runtime
+    pub fn workers(&self) -> usize {
+        self.runtime.get().map_or(0, |o| o.0.metrics().num_workers())
+    }
+
+    /// Tasks alive in the runtime, one or more per connection
+    pub fn tasks(&self) -> usize {
+        self.runtime.get().map_or(0, |o| o.0.metrics().num_alive_tasks())
+    }
+
+    /// Part of time workers were busy since start, from 0 to 1
+    pub fn utilization(&self) -> f64 {
+        let Some((handle, start)) = self.runtime.get() else { return 0.0 };
+        let metrics = handle.metrics();
+
+        let busy: Duration = (0..metrics.num_workers())
+            .map(|o| metrics.worker_total_busy_duration(o))
+            .sum();
+        let total = start.elapsed().as_secs_f64() * metrics.num_workers() as f64;
+
+        if total > 0.0 { (busy.as_secs_f64() / total).min(1.0) } else { 0.0 }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        STATS.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
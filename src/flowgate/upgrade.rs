@@ -0,0 +1,188 @@
+use std::{collections::HashMap, io, net::{TcpListener, TcpStream}, sync::Mutex};
+
+#[cfg(unix)]
+use std::{
+    env,
+    io::{PipeReader, PipeWriter, Read, Write},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    process::Command,
+    sync::{mpsc, LazyLock},
+    thread,
+    time::Duration
+};
+
+#[cfg(unix)]
+use log::{error, info};
+
+/// Listening sockets passed to the new process, `host=fd` separated by commas
+pub const LISTENERS_ENV: &str = "FLOWGATE_LISTENERS";
+/// Pipe the new process writes to when it serves the sockets
+pub const READY_ENV: &str = "FLOWGATE_READY_FD";
+
+/// Time the new process has to start serving
+#[cfg(unix)]
+const READY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Listening sockets by host, to be passed on upgrade
+static LISTENERS: Mutex<Vec<(String, TcpListener)>> = Mutex::new(Vec::new());
+
+/// Sockets inherited from the previous process and not taken yet
+static INHERITED: Mutex<Option<HashMap<String, TcpListener>>> = Mutex::new(None);
+
+/// Pipe that becomes readable once control listeners stop accepting
+#[cfg(unix)]
+static STOP: LazyLock<Option<(PipeReader, PipeWriter)>> = LazyLock::new(|| io::pipe().ok());
+
+/// Takes socket listening on `host` from the previous process, or binds a new one
+pub fn bind(host: &str) -> io::Result<TcpListener> {
+    let listener = match inherited(host) {
+        Some(listener) => listener,
+        None => TcpListener::bind(host)?
+    };
+
+    if let Ok(mut listeners) = LISTENERS.lock() {
+        listeners.push((host.to_string(), listener.try_clone()?));
+    }
+
+    Ok(listener)
+}
+
+#[cfg(unix)]
+fn inherited(host: &str) -> Option<TcpListener> {
+    let mut inherited = INHERITED.lock().ok()?;
+
+    inherited.get_or_insert_with(|| {
+        env::var(LISTENERS_ENV).unwrap_or_default()
+            .split(',')
+            .filter_map(|o| o.split_once('='))
+            .filter_map(|(host, fd)| Some((host.to_string(), fd.parse::<RawFd>().ok()?)))
+            .map(|(host, fd)| unsafe {
+                // Not passed further to processes started by this one
+                libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+                (host, TcpListener::from_raw_fd(fd))
+            })
+            .collect()
+    }).remove(host)
+}
+
+#[cfg(not(unix))]
+fn inherited(_host: &str) -> Option<TcpListener> {
+    None
+}
+
+/// Tells the previous process that sockets are served, so it can drain and exit.
+/// Inherited sockets that are not in the config anymore are closed
+pub fn ready() {
+    if let Ok(mut inherited) = INHERITED.lock() {
+        *inherited = Some(HashMap::new());
+    }
+
+    #[cfg(unix)]
+    if let Some(fd) = env::var(READY_ENV).ok().and_then(|o| o.parse::<RawFd>().ok()) {
+        let mut pipe = std::fs::File::from(unsafe { OwnedFd::from_raw_fd(fd) });
+        let _ = io::Write::write_all(&mut pipe, b"1");
+    }
+}
+
+/// Starts the binary flowgate was run as with the same arguments, passing it listening sockets.
+/// Returns when it serves them, `None` if it failed to start
+#[cfg(unix)]
+pub fn exec() -> Option<()> {
+    let program = env::args_os().next()?;
+
+    let listeners: Vec<(String, OwnedFd)> = LISTENERS.lock().ok()?.iter()
+        .filter_map(|(host, listener)| Some((host.clone(), inheritable(listener.as_raw_fd())?)))
+        .collect();
+
+    let (mut reader, pipe_writer) = io::pipe().ok()?;
+    let writer = inheritable(pipe_writer.as_raw_fd())?;
+    drop(pipe_writer);
+
+    info!("Upgrading, starting {}", program.to_string_lossy());
+
+    let child = Command::new(&program)
+        .args(env::args_os().skip(1))
+        .env(LISTENERS_ENV, listeners.iter().map(|(host, fd)| format!("{host}={}", fd.as_raw_fd())).collect::<Vec<_>>().join(","))
+        .env(READY_ENV, writer.as_raw_fd().to_string())
+        .spawn();
+
+    // Only the new process keeps the write end, so the pipe closes if it exits
+    drop(listeners);
+    drop(writer);
+
+    let mut child = match child {
+        Ok(child) => child,
+        Err(e) => {
+            error!("Upgrade failed, can't start {}: {e}", program.to_string_lossy());
+            return None;
+        }
+    };
+
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buf = [0; 1];
+        let _ = sender.send(reader.read(&mut buf).is_ok_and(|o| o > 0));
+    });
+
+    if receiver.recv_timeout(READY_TIMEOUT).unwrap_or(false) {
+        info!("Upgrade done, new process {} serves the sockets", child.id());
+        Some(())
+    } else {
+        let _ = child.kill();
+        let _ = child.wait();
+        error!("Upgrade failed, new process {} didn't start serving", child.id());
+        None
+    }
+}
+
+/// Accepts connection on control `listener`, `None` once control listeners are stopped.
+/// Sockets handed to the new process stay open, but this one doesn't take connections from them
+#[cfg(unix)]
+pub fn accept(listener: &TcpListener) -> Option<io::Result<TcpStream>> {
+    let stop = STOP.as_ref()?;
+
+    // Both processes may wait on the socket, so the one that loses a connection goes on waiting
+    if let Err(e) = listener.set_nonblocking(true) {
+        return Some(Err(e));
+    }
+
+    loop {
+        let mut fds = [
+            libc::pollfd { fd: listener.as_raw_fd(), events: libc::POLLIN, revents: 0 },
+            libc::pollfd { fd: stop.0.as_raw_fd(), events: libc::POLLIN, revents: 0 }
+        ];
+
+        if unsafe { libc::poll(fds.as_mut_ptr(), 2, -1) } < 0 {
+            continue;
+        }
+        if fds[1].revents != 0 {
+            return None;
+        }
+
+        match listener.accept() {
+            Ok((stream, _)) => return Some(stream.set_nonblocking(false).map(|_| stream)),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(e) => return Some(Err(e))
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub fn accept(listener: &TcpListener) -> Option<io::Result<TcpStream>> {
+    Some(listener.accept().map(|o| o.0))
+}
+
+/// Stops accepting on control listeners, e.g. before draining
+pub fn stop_accepting() {
+    #[cfg(unix)]
+    if let Some((_, writer)) = STOP.as_ref() {
+        let _ = (&*writer).write_all(b"1");
+    }
+}
+
+/// Duplicates `fd` without close-on-exec, so a started process inherits it
+#[cfg(unix)]
+fn inheritable(fd: RawFd) -> Option<OwnedFd> {
+    let fd = unsafe { libc::dup(fd) };
+    (fd >= 0).then(|| unsafe { OwnedFd::from_raw_fd(fd) })
+}
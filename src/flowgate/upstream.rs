@@ -0,0 +1,261 @@
+use std::{collections::BTreeMap, io, net::{TcpStream, ToSocketAddrs}, pin::Pin, sync::Mutex, task::{Context, Poll}, time::{Duration, Instant}};
+
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use super::{closeable::Closeable, forward::{AsTcp, Tcp}};
+
+/// Open connections to site servers by domain
+static CONNECTIONS: Mutex<BTreeMap<String, usize>> = Mutex::new(BTreeMap::new());
+
+/// Idle keep-alive connections to a site server with time they expire
+type IdleConnections = Vec<(AsyncUpstreamStream, Instant)>;
+
+/// Idle keep-alive connections to site servers by domain and host
+static POOL: Mutex<BTreeMap<(String, String), IdleConnections>> = Mutex::new(BTreeMap::new());
+
+/// Keeps connection counted for its site until dropped
+struct ConnectionSlot(String);
+
+impl ConnectionSlot {
+    fn acquire(domain: &str, limit: Option<usize>) -> Option<ConnectionSlot> {
+        let mut connections = CONNECTIONS.lock().ok()?;
+        let count = connections.entry(domain.to_string()).or_insert(0);
+
+        if limit.is_some_and(|o| *count >= o) {
+            return None;
+        }
+
+        *count += 1;
+        Some(ConnectionSlot(domain.to_string()))
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        if let Ok(mut connections) = CONNECTIONS.lock() {
+            if let Some(count) = connections.get_mut(&self.0) {
+                *count -= 1;
+                if *count == 0 {
+                    connections.remove(&self.0);
+                }
+            }
+        }
+    }
+}
+
+/// Drops idle connections that expired
+pub fn expire_idle() {
+    let Ok(mut pool) = POOL.lock() else { return };
+
+    for idle in pool.values_mut() {
+        idle.retain(|o| o.1 > Instant::now());
+    }
+    pool.retain(|_, idle| !idle.is_empty());
+}
+
+/// Returns count of idle connections kept for reuse
+pub fn idle_connections() -> usize {
+    POOL.lock().map_or(0, |o| o.values().map(Vec::len).sum())
+}
+
+/// Returns count of open connections to server of site `domain`
+pub fn connections(domain: &str) -> usize {
+    CONNECTIONS.lock().ok()
+        .and_then(|o| o.get(domain).copied())
+        .unwrap_or(0)
+}
+
+pub enum UpstreamStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream)
+}
+
+impl UpstreamStream {
+    /// Connects to `host`, which is either `addr:port` or `unix:/path/to/socket`.
+    /// `timeout` applies to TCP connections only
+    pub fn connect(host: &str, timeout: Option<Duration>) -> Option<UpstreamStream> {
+        #[cfg(unix)]
+        if let Some(path) = host.strip_prefix("unix:") {
+            return UnixStream::connect(path).ok().map(UpstreamStream::Unix);
+        }
+
+        let stream = match timeout {
+            Some(timeout) => host.to_socket_addrs().ok()?
+                .find_map(|addr| TcpStream::connect_timeout(&addr, timeout).ok())?,
+            None => TcpStream::connect(host).ok()?
+        };
+
+        Some(UpstreamStream::Tcp(stream))
+    }
+}
+
+impl Closeable for UpstreamStream {
+    fn close(&self) {
+        match self {
+            UpstreamStream::Tcp(stream) => stream.close(),
+            #[cfg(unix)]
+            UpstreamStream::Unix(stream) => stream.close()
+        }
+    }
+}
+
+enum AsyncSocket {
+    Tcp(tokio::net::TcpStream),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixStream)
+}
+
+/// Non-blocking connection to site server, used by the async proxy core
+pub struct AsyncUpstreamStream {
+    socket: AsyncSocket,
+    _slot: Option<ConnectionSlot>
+}
+
+impl AsyncUpstreamStream {
+    /// Connects to `host`, which is either `addr:port` or `unix:/path/to/socket`
+    pub async fn connect(host: &str, timeout: Option<Duration>) -> Option<AsyncUpstreamStream> {
+        let connect = async {
+            #[cfg(unix)]
+            if let Some(path) = host.strip_prefix("unix:") {
+                return tokio::net::UnixStream::connect(path).await.ok().map(AsyncSocket::Unix);
+            }
+
+            let stream = tokio::net::TcpStream::connect(host).await.ok()?;
+            let _ = stream.set_nodelay(true);
+            Some(AsyncSocket::Tcp(stream))
+        };
+
+        let socket = match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, connect).await.ok()??,
+            None => connect.await?
+        };
+
+        Some(AsyncUpstreamStream { socket, _slot: None })
+    }
+
+    /// Takes the latest idle connection to `host` of site `domain`, dropping expired and closed ones
+    pub fn reuse(domain: &str, host: &str) -> Option<AsyncUpstreamStream> {
+        let mut pool = POOL.lock().ok()?;
+        let idle = pool.get_mut(&(domain.to_string(), host.to_string()))?;
+
+        while let Some((stream, expires)) = idle.pop() {
+            if expires > Instant::now() && stream.is_alive() {
+                return Some(stream);
+            }
+        }
+
+        None
+    }
+
+    /// Keeps connection for reuse during `idle_timeout`, unless `max_idle` connections
+    /// to the host are kept already. Connections to other hosts of the site are dropped
+    pub fn release(self, domain: &str, host: &str, max_idle: usize, idle_timeout: Duration) {
+        let Ok(mut pool) = POOL.lock() else { return };
+
+        pool.retain(|key, _| key.0 != domain || key.1 == host);
+
+        let idle = pool.entry((domain.to_string(), host.to_string())).or_default();
+        if idle.len() < max_idle {
+            idle.push((self, Instant::now() + idle_timeout));
+        }
+    }
+
+    /// Checks that idle connection is not closed by the server and has no unexpected data
+    fn is_alive(&self) -> bool {
+        let mut buf = [0; 1];
+        let result = match &self.socket {
+            AsyncSocket::Tcp(stream) => stream.try_read(&mut buf),
+            #[cfg(unix)]
+            AsyncSocket::Unix(stream) => stream.try_read(&mut buf)
+        };
+
+        matches!(result, Err(e) if e.kind() == io::ErrorKind::WouldBlock)
+    }
+
+    /// Connects to server of site `domain` if it has less than `limit` open connections
+    pub async fn connect_limited(
+        domain: &str,
+        host: &str,
+        timeout: Option<Duration>,
+        limit: Option<usize>
+    ) -> Option<AsyncUpstreamStream> {
+        let slot = ConnectionSlot::acquire(domain, limit)?;
+        let mut stream = AsyncUpstreamStream::connect(host, timeout).await?;
+        stream._slot = Some(slot);
+        Some(stream)
+    }
+
+    /// Starts TLS session over the stream, negotiating HTTP/2 via ALPN
+    #[cfg(feature = "use-openssl")]
+    pub async fn connect_tls(self, domain: &str, verify: bool) -> Option<tokio_openssl::SslStream<AsyncUpstreamStream>> {
+        use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+
+        let mut connector = SslConnector::builder(SslMethod::tls()).ok()?;
+        connector.set_alpn_protos(b"\x02h2").ok()?;
+        if !verify {
+            connector.set_verify(SslVerifyMode::NONE);
+        }
+
+        let mut ssl = connector.build().configure().ok()?;
+        ssl.set_verify_hostname(verify);
+
+        let mut stream = tokio_openssl::SslStream::new(ssl.into_ssl(domain).ok()?, self).ok()?;
+        Pin::new(&mut stream).connect().await.ok()?;
+
+        if stream.ssl().selected_alpn_protocol() != Some(b"h2") {
+            return None;
+        }
+
+        Some(stream)
+    }
+}
+
+impl AsyncRead for AsyncUpstreamStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().socket {
+            AsyncSocket::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            AsyncSocket::Unix(stream) => Pin::new(stream).poll_read(cx, buf)
+        }
+    }
+}
+
+impl AsyncWrite for AsyncUpstreamStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match &mut self.get_mut().socket {
+            AsyncSocket::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            AsyncSocket::Unix(stream) => Pin::new(stream).poll_write(cx, buf)
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().socket {
+            AsyncSocket::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            AsyncSocket::Unix(stream) => Pin::new(stream).poll_flush(cx)
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().socket {
+            AsyncSocket::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            AsyncSocket::Unix(stream) => Pin::new(stream).poll_shutdown(cx)
+        }
+    }
+}
+
+impl AsTcp for AsyncUpstreamStream {
+    fn as_tcp(&self) -> Option<Tcp<'_>> {
+        match &self.socket {
+            AsyncSocket::Tcp(stream) => stream.as_tcp(),
+            #[cfg(unix)]
+            AsyncSocket::Unix(_) => None
+        }
+    }
+}
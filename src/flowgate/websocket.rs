@@ -1,54 +1,224 @@
-use std::sync::{Arc, RwLock};
-
-use serde_json::Value;
-use websocket::{sync::Server, OwnedMessage};
-
-use super::config::{Config, IpForwarding, SiteConfig};
-
-fn on_message(config: Arc<RwLock<Config>>, data: Value) -> Option<()> {
-    let data = data.as_object()?;
-    if data.get("type")?.as_str()? == "set_site" {
-        let mut conf = config.write().ok()?;
-        let domain = data.get("domain")?.as_str()?;
-
-        if let Some(site) = conf.sites.iter_mut().filter(|o| o.domain == domain).next() {
-            site.host = data.get("host")?.as_str()?.to_string();
-            site.enable_keep_alive = data.get("enable_keep_alive")?.as_bool()?;
-            site.support_keep_alive = data.get("support_keep_alive")?.as_bool()?;
-            site.ip_forwarding = IpForwarding::from_name(data.get("ip_forwarding")?.as_str()?)?;
-        } else {
-            conf.sites.push(SiteConfig {
-                domain: domain.to_string(),
-                host: data.get("host")?.as_str()?.to_string(),
-                enable_keep_alive: data.get("enable_keep_alive")?.as_bool()?,
-                support_keep_alive: data.get("support_keep_alive")?.as_bool()?,
-                ip_forwarding: IpForwarding::from_name(data.get("ip_forwarding")?.as_str()?)?,
-                replace_host: data.get("replace_host").map(|o| o.as_str()).flatten().map(|o| o.to_string()),
-                ssl: None
-            });
+use std::{
+    cell::Cell,
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{mpsc::{self, Receiver, Sender}, Arc, RwLock},
+    thread,
+    time::{Duration, Instant}
+};
+
+use log::{info, warn};
+use serde_json::{Map, Value};
+use websocket::{sync::{server::upgrade::IntoWs, Client, Stream}, OwnedMessage};
+
+use super::{config::{Config, ControlToken}, control::{self, ControlError, ControlGuard}, upgrade};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+fn text_frame(text: &str) -> Vec<u8> {
+    let mut frame = vec![0x81];
+    match text.len() {
+        len if len < 126 => frame.push(len as u8),
+        len if len <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend((len as u16).to_be_bytes());
+        },
+        len => {
+            frame.push(127);
+            frame.extend((len as u64).to_be_bytes());
         }
     }
+    frame.extend(text.as_bytes());
+    frame
+}
 
-    Some(())
+/// Client stream that pushes pending events while waiting for client messages.
+/// Reads fail after `deadline` until the client authenticates.
+/// Underlying socket must have read timeout set
+struct EventStream<S> {
+    inner: S,
+    events: Receiver<Value>,
+    deadline: Cell<Option<Instant>>
 }
 
-pub fn start_server(config: Arc<RwLock<Config>>) -> Option<()> {
-    let mut server = Server::bind(config.read().ok()?.websocket_host.clone()?).ok()?;
+impl<S> EventStream<S> {
+    fn authenticated(&self) {
+        self.deadline.set(None);
+    }
+}
+
+impl<S: Read + Write> Read for EventStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.deadline.get().is_some_and(|o| o <= Instant::now()) {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
 
-    while let Ok(res) = server.accept() {
-        let mut res = res.accept().ok()?;
-        for msg in res.incoming_messages() {
-            if let Ok(OwnedMessage::Text(msg)) = msg {
-                if let Ok(data) = serde_json::from_str(&msg) {
-                    if let None = on_message(config.clone(), data) {
-                        break
+            match self.inner.read(buf) {
+                Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                    while let Ok(event) = self.events.try_recv() {
+                        self.inner.write_all(&text_frame(&event.to_string()))?;
                     }
-                }
-            } else {
-                break
+                    self.inner.flush()?;
+                },
+                result => return result
             }
         }
     }
+}
+
+impl<S: Write> Write for EventStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn serve_client<S: Stream>(
+    config: Arc<RwLock<Config>>, 
+    mut client: Client<EventStream<S>>, 
+    addr: SocketAddr, 
+    events: Sender<Value>
+) -> Option<()> {
+    let mut token: Option<ControlToken> = None;
+
+    while let Ok(OwnedMessage::Text(msg)) = client.recv_message() {
+        let data = match serde_json::from_str::<Value>(&msg) {
+            Ok(Value::Object(data)) => data,
+            Ok(_) => Map::new(),
+            Err(_) => {
+                let reply = control::make_reply(None, None, Err(ControlError::new("invalid_json")));
+                client.send_message(&OwnedMessage::Text(reply.to_string())).ok()?;
+                continue
+            }
+        };
+
+        let id = data.get("id");
+        let kind = data.get("type").and_then(|o| o.as_str());
+
+        let (result, close) = match &token {
+            Some(token) if kind == Some("subscribe") => {
+                control::subscribe(events.clone(), token.clone());
+                (Ok(Value::Null), false)
+            },
+            Some(token) => (control::on_message(config.clone(), token, addr, &data), false),
+            None if kind == Some("auth") => match control::get_str(&data, "token")
+                .and_then(|token| control::authenticate(&config, addr, token)) {
+                Ok(t) => {
+                    info!("{addr} > control client authenticated");
+                    client.stream_ref().authenticated();
+                    token = Some(t);
+                    (Ok(Value::Null), false)
+                },
+                Err(error) => (Err(error), true)
+            },
+            None => (Err(control::unauthorized(kind.unwrap_or_default(), addr)), false)
+        };
+
+        let reply = control::make_reply(id, kind, result);
+        client.send_message(&OwnedMessage::Text(reply.to_string())).ok()?;
+
+        if close { break }
+    }
+
+    Some(())
+}
+
+fn accept_client<S: Read + Write>(
+    config: Arc<RwLock<Config>>, 
+    stream: S, 
+    addr: SocketAddr
+) -> Option<()> {
+    let (sender, events) = mpsc::channel();
+    let deadline = Instant::now() + config.read().ok()?.connection_timeout;
+
+    let Some(client) = EventStream { inner: stream, events, deadline: Cell::new(Some(deadline)) }.into_ws().ok()
+        .and_then(|o| o.accept().ok()) else {
+        warn!("{addr} > websocket handshake failed");
+        return None;
+    };
+
+    serve_client(config, client, addr, sender)
+}
+
+fn accept_stream(
+    config: Arc<RwLock<Config>>, 
+    stream: TcpStream, 
+    addr: SocketAddr,
+    #[cfg(feature = "use-openssl")]
+    acceptor: Option<openssl::ssl::SslAcceptor>
+) -> Option<()> {
+    #[cfg(feature = "use-openssl")]
+    if let Some(acceptor) = acceptor {
+        stream.set_read_timeout(Some(config.read().ok()?.connection_timeout)).ok()?;
+
+        let mut ssl = openssl::ssl::Ssl::new(acceptor.context()).ok()?;
+        config.read().ok()?.websocket_ssl.as_ref()?.apply(&mut ssl)?;
+
+        let Ok(stream) = ssl.accept(stream) else {
+            warn!("{addr} > websocket tls handshake failed");
+            return None;
+        };
+
+        stream.get_ref().set_read_timeout(Some(POLL_INTERVAL)).ok()?;
+        return accept_client(config, stream, addr);
+    }
+
+    stream.set_read_timeout(Some(POLL_INTERVAL)).ok()?;
+    accept_client(config, stream, addr)
+}
+
+pub fn start_server(config: Arc<RwLock<Config>>, listener: TcpListener) -> Option<()> {
+    let host = config.read().ok()?.websocket_host.clone()?;
+
+    #[cfg(feature = "use-openssl")]
+    let acceptor = match &config.read().ok()?.websocket_ssl {
+        Some(_) => Some(openssl::ssl::SslAcceptor::mozilla_intermediate(openssl::ssl::SslMethod::tls()).ok()?.build()),
+        None => None
+    };
+
+    if config.read().ok()?.websocket_tokens.is_empty() {
+        warn!("No websocket tokens configured, control messages will be rejected");
+    }
+
+    if let Some(interval) = config.read().ok()?.health_check_interval {
+        thread::spawn({
+            let config = config.clone();
+            move || control::run_health_checks(config, interval)
+        });
+    }
+
+    info!("Websocket server runned on {host}");
+
+    while let Some(stream) = upgrade::accept(&listener) {
+        let Ok(stream) = stream else { continue };
+        let Ok(addr) = stream.peer_addr() else { continue };
+
+        let Some(guard) = ControlGuard::new() else {
+            warn!("{addr} > too many control connections");
+            continue
+        };
+
+        thread::spawn({
+            let config = config.clone();
+            #[cfg(feature = "use-openssl")]
+            let acceptor = acceptor.clone();
+
+            move || {
+                let _guard = guard;
+                accept_stream(
+                    config, 
+                    stream, 
+                    addr,
+                    #[cfg(feature = "use-openssl")]
+                    acceptor
+                )
+            }
+        });
+    }
 
     Some(())
 }
\ No newline at end of file
@@ -1,22 +1,204 @@
-use std::{fs, path::Path, sync::{Arc, RwLock}};
+use std::{env, fs, io::{self, Write}, net::TcpListener, path::Path, process, str::FromStr, sync::{Arc, RwLock}, thread};
 
-use flowgate::{config::Config, server::FlowgateServer, websocket};
+use log::{error, info, LevelFilter};
+
+use flowgate::{admin, config::Config, control, server::FlowgateServer, upgrade, websocket};
+
+const HELP: &str = "Usage: flowgate [OPTIONS]
+
+Options:
+  -c, --config <PATH>         Config file, .yml, .json or .toml (env FLOWGATE_CONFIG, default - conf.yml)
+      --check-config          Check config file, report all problems and exit
+      --print-default-config  Print default config and exit
+      --log-level <LEVEL>     off, error, warn, info, debug or trace (env FLOWGATE_LOG_LEVEL, default - info)
+      --log-format <FORMAT>   color, plain or json (env FLOWGATE_LOG_FORMAT, default - color)
+  -h, --help                  Print help and exit";
+
+const DEFAULT_CONFIG: &[u8] = include_bytes!("../conf.yml");
+
+struct Args {
+    config: Option<String>,
+    check_config: bool,
+    print_default_config: bool,
+    log_level: LevelFilter,
+    log_format: String
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = Args {
+        config: env::var("FLOWGATE_CONFIG").ok(),
+        check_config: false,
+        print_default_config: false,
+        log_level: LevelFilter::Info,
+        log_format: env::var("FLOWGATE_LOG_FORMAT").unwrap_or("color".to_string())
+    };
+
+    let mut log_level = env::var("FLOWGATE_LOG_LEVEL").ok();
+    let mut iter = env::args().skip(1);
+
+    while let Some(arg) = iter.next() {
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name.to_string(), Some(value.to_string())),
+            None => (arg, None)
+        };
+
+        let mut value = || value.clone().or_else(|| iter.next())
+            .ok_or(format!("missing value for {name}"));
+
+        match name.as_str() {
+            "-c" | "--config" => args.config = Some(value()?),
+            "--check-config" => args.check_config = true,
+            "--print-default-config" => args.print_default_config = true,
+            "--log-level" => log_level = Some(value()?),
+            "--log-format" => args.log_format = value()?,
+            "-h" | "--help" => {
+                let _ = writeln!(io::stdout(), "{HELP}");
+                process::exit(0);
+            },
+            _ => return Err(format!("unknown option {name}"))
+        }
+    }
+
+    if let Some(level) = log_level {
+        args.log_level = LevelFilter::from_str(&level).map_err(|_| format!("invalid log level {level}"))?;
+    }
+
+    if !["color", "plain", "json"].contains(&args.log_format.as_str()) {
+        return Err(format!("invalid log format {}", args.log_format));
+    }
+
+    Ok(args)
+}
+
+fn init_logger(args: &Args) {
+    let mut builder = match args.log_format.as_str() {
+        "plain" => {
+            let mut builder = colog::basic_builder();
+            builder.format(|buf, record| writeln!(buf, "[{}] {}", record.level(), record.args()));
+            builder
+        },
+        "json" => {
+            let mut builder = colog::basic_builder();
+            builder.format(|buf, record| writeln!(buf, "{}", serde_json::json!({
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "message": record.args().to_string()
+            })));
+            builder
+        },
+        _ => colog::basic_builder()
+    };
+
+    builder.filter_level(args.log_level);
+    builder.init();
+}
+
+/// Listens on control `host` if it is set, reusing the socket of the previous process on upgrade
+fn listen(host: Option<String>) -> Option<TcpListener> {
+    let host = host?;
+    upgrade::bind(&host).inspect_err(|e| error!("Can't listen on {host}: {e}")).ok()
+}
 
 fn main() {
-    colog::init();
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(error) => {
+            eprintln!("{error}\n\n{HELP}");
+            process::exit(2);
+        }
+    };
 
-    if !Path::new("conf.yml").exists() {
-        let _ = fs::write("conf.yml", include_bytes!("../conf.yml"));
+    if args.print_default_config {
+        let _ = io::stdout().write_all(DEFAULT_CONFIG);
+        return;
     }
 
-    let config = Arc::new(RwLock::new(Config::parse("conf.yml").unwrap()));
+    init_logger(&args);
+
+    let filename = match &args.config {
+        Some(filename) => filename.clone(),
+        None => {
+            if !Path::new("conf.yml").exists() {
+                let _ = fs::write("conf.yml", DEFAULT_CONFIG);
+            }
+            "conf.yml".to_string()
+        }
+    };
+
+    let config = match Config::parse(&filename) {
+        Ok(config) => config,
+        Err(errors) => {
+            for error in errors {
+                error!("{error}");
+            }
+            process::exit(1);
+        }
+    };
+
+    if args.check_config {
+        info!("{filename} is valid");
+        return;
+    }
+
+    let config = Arc::new(RwLock::new(config));
     let server = FlowgateServer::new(config.clone());
 
     server.start();
 
-    if config.read().unwrap().websocket_host.is_some() {
-        websocket::start_server(config);
-    } else {
-        loop {}
+    let admin_listener = listen(config.read().unwrap().admin_host.clone());
+    let websocket_listener = listen(config.read().unwrap().websocket_host.clone());
+
+    upgrade::ready();
+
+    #[cfg(unix)]
+    thread::spawn({
+        use signal_hook::{consts::{SIGHUP, SIGINT, SIGTERM, SIGUSR2}, iterator::Signals};
+
+        let config = config.clone();
+        let mut signals = Signals::new([SIGHUP, SIGINT, SIGTERM, SIGUSR2]).unwrap();
+        let mut server = Some(server);
+
+        move || {
+            for signal in signals.forever() {
+                match signal {
+                    SIGHUP => {
+                        let _ = control::reload_config(&config);
+                        continue;
+                    },
+                    // New binary took the sockets, this process drains as on SIGTERM
+                    SIGUSR2 if server.is_none() || upgrade::exec().is_none() => continue,
+                    _ => {}
+                }
+
+                // Second signal stops without waiting for the drain
+                let Some(server) = server.take() else {
+                    info!("Forced exit");
+                    process::exit(1);
+                };
+
+                // Control clients are served by the new process or none
+                upgrade::stop_accepting();
+
+                let timeout = config.read().unwrap().drain_timeout;
+                thread::spawn(move || {
+                    server.shutdown(timeout);
+                    process::exit(0);
+                });
+            }
+        }
+    });
+
+    if let Some(listener) = admin_listener {
+        thread::spawn({
+            let config = config.clone();
+            move || admin::start_server(config, listener)
+        });
+    }
+
+    if let Some(listener) = websocket_listener {
+        websocket::start_server(config, listener);
     }
+
+    // Process exits from the signal thread once drained
+    loop { thread::park() }
 }
\ No newline at end of file